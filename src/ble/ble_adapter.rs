@@ -8,25 +8,22 @@ use bluer::{
             CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicWrite, CharacteristicWriteMethod,
             Service,
         },
-        CharacteristicReader, CharacteristicWriter,
     },
 };
-use futures::{future, pin_mut, StreamExt};
+use futures::{pin_mut, StreamExt};
 use std::{collections::BTreeMap, time::Duration};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    sync::broadcast,
-    time::{interval, sleep},
+    io::{AsyncBufReadExt, BufReader},
+    sync::mpsc,
+    time::sleep,
 };
-use bytes::{Bytes, Buf};
 
 use super::gatt::{SERVICE_UUID, CHARACTERISTIC_UUID, MANUFACTURER_ID};
+use super::session::Sessions;
+use crate::bus::{fanout::RecvError, Fanout, FromBle, Subscriber, ToBle};
 
-const DEFAULT_MTU: usize = 23;
-const ATT_HEADER_SIZE: usize = 3;
 
-
-pub async fn configure(mut subs: broadcast::Receiver<Bytes>, transmitter: broadcast::Sender<Bytes>) -> bluer::Result<()> {
+pub async fn configure(mut subs: Subscriber<ToBle>, transmitter: Fanout<FromBle>) -> bluer::Result<()> {
     println!("will config");
 
     env_logger::init();
@@ -94,13 +91,9 @@ pub async fn configure(mut subs: broadcast::Receiver<Bytes>, transmitter: broadc
     let stdin = BufReader::new(tokio::io::stdin());
     let mut lines = stdin.lines();
 
-    let mut value: Vec<u8>;// = vec![0x10, 0x01, 0x01, 0x10];
-    let mut read_buf = Vec::new();
-    let mut reader_opt: Option<CharacteristicReader> = None;
-    let mut writer_opt: Option<CharacteristicWriter> = None;
-    let mtu: usize;
-    //writer_opt.unwrap().mtu()
-    //let mut interval = interval(Duration::from_secs(1));
+    // One session per connected central
+    let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+    let mut sessions = Sessions::new(transmitter, closed_tx);
 
 
     pin_mut!(char_control);
@@ -111,78 +104,41 @@ pub async fn configure(mut subs: broadcast::Receiver<Bytes>, transmitter: broadc
             evt = char_control.next() => {
                 match evt {
                     Some(CharacteristicControlEvent::Write(req)) => {
-                        println!("Accepting write event with MTU {} from {}", req.mtu(), req.device_address());
-                        read_buf = vec![0; req.mtu()];
-                        reader_opt = Some(req.accept()?);
+                        let address = req.device_address();
+                        println!("Accepting write event with MTU {} from {}", req.mtu(), address);
+                        sessions.accept_reader(address, req.accept()?);
                     },
                     Some(CharacteristicControlEvent::Notify(notifier)) => {
-                        println!("Accepting notify request event with MTU {} from {}", notifier.mtu(), notifier.device_address());
-                        writer_opt = Some(notifier);
+                        let address = notifier.device_address();
+                        println!("Accepting notify request event with MTU {} from {}", notifier.mtu(), address);
+                        sessions.accept_notifier(address, notifier);
                     },
                     None => break,
                 }
             }
 
+            // A central's write stream ended
+            Some((address, reader)) = closed_rx.recv() => {
+                sessions.reader_closed(address, reader);
+            }
+
             // Recive messages from the Server via subscription
-            // and send as notification to central.
+            // and send as notification to the centrals they are routed to.
             msg = subs.recv() => {
                 match msg {
                     Ok(m) => {
-                        println!("***recv from server: {:?}", m);
-                        if let Some(writer) = writer_opt.as_mut() {
-                            //println!("Notifying with value {:x?}", &value);
-                            let chunk_size = writer.mtu() - ATT_HEADER_SIZE;
-
-                            for chunk in m.chunks(chunk_size) {
-                                if let Err(err) = writer.write(&chunk).await {
-                                    println!("notification stream error: {}", &err);
-                                    writer_opt = None;
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => eprintln!("channel recv err: {}", e)
-                }
-            }
-
-            //_ = interval.tick() => {
-                //println!("Decrementing each element by one");
-                /*for v in &mut *value {
-                    *v = v.saturating_sub(1);
-                }
-                //println!("Value is {:x?}", &value);
-                if let Some(writer) = writer_opt.as_mut() {
-                    println!("Notifying with value {:x?}", &value);
-                    if let Err(err) = writer.write(&value).await {
-                        println!("Notification stream error: {}", &err);
-                        writer_opt = None;
-                    }
-                }*/
-            //}
-
-            // Handle writes from central
-            read_res = async {
-                match &mut reader_opt {
-                    Some(reader) => reader.read(&mut read_buf).await,
-                    None => future::pending().await,
-                }
-            } => {
-                match read_res {
-                    Ok(0) => {
-                        println!("Write stream ended");
-                        reader_opt = None;
+                        println!("***recv from server for {:?}", m.route);
+                        sessions.notify(m.route, &m.message).await;
                     }
-                    Ok(n) => {
-                        value = read_buf[0..n].to_vec();
-                        println!("Write request with {} bytes: {:x?}", n, &value);
-                        if let Err(e) = transmitter.send(Bytes::from(value)) {
-                            eprintln!("ble could not transmit: {}", e);
-                        }
+                    Err(RecvError::Lagged(n)) => eprintln!("ble lagged behind the server, {} messages dropped", n),
+                    // A slow radio loses the backlog, not the service
+                    Err(RecvError::Disconnected) => {
+                        eprintln!("ble fell too far behind the server, queued messages dropped");
+                        subs.reconnect();
                     }
-                    Err(err) => {
-                        println!("Write stream error: {}", &err);
-                        reader_opt = None;
+                    Err(e) => {
+                        eprintln!("channel recv err: {}", e);
+                        break;
                     }
                 }
             }
@@ -1,5 +1,6 @@
 pub mod gatt;
 pub mod ble_adapter;
+pub mod session;
 pub mod transport;
 
 /// Re-export the configure function for easy access as `ble::configure()`
@@ -0,0 +1,246 @@
+//! Per-central state, so several phones can be connected at once.
+
+use bluer::{
+    gatt::{CharacteristicReader, CharacteristicWriter},
+    Address,
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    task::{self, JoinHandle},
+    time::interval,
+};
+
+use super::transport::{fragment, Reassembler};
+use crate::bus::{Fanout, FromBle, Route};
+use crate::proto::{binary::Encoding, msg::Message};
+
+pub const DEFAULT_MTU: usize = 23;
+pub const ATT_HEADER_SIZE: usize = 3;
+
+/// One connected central, keyed by its device address.
+pub struct Session {
+    pub address: Address,
+    pub mtu: usize,
+    notifier: Option<CharacteristicWriter>,
+    reader_task: Option<JoinHandle<()>>,
+    next_msg_id: u16,
+    /// How the central last wrote to us, it gets answered the same way.
+    encoding: Arc<Mutex<Encoding>>,
+}
+
+impl Session {
+    fn new(address: Address) -> Self {
+        Self {
+            address,
+            mtu: DEFAULT_MTU,
+            notifier: None,
+            reader_task: None,
+            next_msg_id: 0,
+            encoding: Arc::new(Mutex::new(Encoding::Text)),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.notifier.is_none() && self.reader_task.is_none()
+    }
+
+    /// Encodes `message` the way the central speaks, falling back to text
+    /// if it does not fit the binary encoding.
+    fn encode(&self, message: &Message) -> Vec<u8> {
+        let encoding = *self.encoding.lock().unwrap();
+        match message.encode_as(encoding) {
+            Ok(payload) => payload.to_vec(),
+            Err(e) => {
+                eprintln!("could not encode message for {} as {}, sending text: {}", self.address, encoding, e);
+                message.encode_as(Encoding::Text).map(|p| p.to_vec()).unwrap_or_default()
+            }
+        }
+    }
+
+    /// Fragments `message` and sends it as notifications.
+    /// Drops the notifier if the central went away.
+    async fn notify(&mut self, message: &Message) {
+        if self.notifier.is_none() {
+            return;
+        }
+        let msg = self.encode(message);
+        let Some(writer) = self.notifier.as_mut() else {
+            return;
+        };
+
+        let chunk_size = writer.mtu().max(DEFAULT_MTU) - ATT_HEADER_SIZE;
+
+        let msg_id = self.next_msg_id;
+        self.next_msg_id = self.next_msg_id.wrapping_add(1);
+
+        let chunks = match fragment(msg_id, &msg, chunk_size) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not fragment notification for {}: {}", self.address, e);
+                return;
+            }
+        };
+
+        for chunk in chunks {
+            if let Err(err) = writer.write(&chunk).await {
+                println!("notification stream error for {}: {}", self.address, &err);
+                self.notifier = None;
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(task) = self.reader_task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// All connected centrals.
+pub struct Sessions {
+    sessions: HashMap<Address, Session>,
+    transmitter: Fanout<FromBle>,
+    closed_tx: mpsc::UnboundedSender<(Address, task::Id)>,
+}
+
+impl Sessions {
+    /// `closed_tx` is told when a central's write stream ends, the owner
+    /// must then call `reader_closed` with what it received.
+    pub fn new(transmitter: Fanout<FromBle>, closed_tx: mpsc::UnboundedSender<(Address, task::Id)>) -> Self {
+        Self {
+            sessions: HashMap::new(),
+            transmitter,
+            closed_tx,
+        }
+    }
+
+    fn entry(&mut self, address: Address) -> &mut Session {
+        self.sessions.entry(address).or_insert_with(|| {
+            println!("central {} connected", address);
+            Session::new(address)
+        })
+    }
+
+    /// Starts reading writes from `address`, replacing its previous reader.
+    pub fn accept_reader(&mut self, address: Address, reader: CharacteristicReader) {
+        let transmitter = self.transmitter.clone();
+        let closed_tx = self.closed_tx.clone();
+
+        let session = self.entry(address);
+        session.mtu = reader.mtu();
+        let encoding = session.encoding.clone();
+
+        if let Some(old) = session.reader_task.take() {
+            old.abort();
+        }
+
+        session.reader_task = Some(tokio::spawn(async move {
+            read_central(address, reader, transmitter, encoding).await;
+            let _ = closed_tx.send((address, task::id()));
+        }));
+    }
+
+    /// Sends notifications to `address` through `notifier`, replacing its previous one.
+    pub fn accept_notifier(&mut self, address: Address, notifier: CharacteristicWriter) {
+        let session = self.entry(address);
+        session.mtu = notifier.mtu();
+        session.notifier = Some(notifier);
+    }
+
+    pub fn reader_closed(&mut self, address: Address, reader: task::Id) {
+        if let Some(session) = self.sessions.get_mut(&address) {
+            // A newer reader may have replaced the one that ended
+            if session.reader_task.as_ref().is_some_and(|t| t.id() == reader) {
+                session.reader_task = None;
+            }
+        }
+        self.remove_idle();
+    }
+
+    /// Delivers `message` to the centrals selected by `route`.
+    pub async fn notify(&mut self, route: Route, message: &Message) {
+        match route {
+            Route::Broadcast => {
+                for session in self.sessions.values_mut() {
+                    session.notify(message).await;
+                }
+            }
+            Route::Central(address) => match self.sessions.get_mut(&address) {
+                Some(session) => session.notify(message).await,
+                None => eprintln!("no session for central {}, message dropped", address),
+            },
+        }
+        self.remove_idle();
+    }
+
+    fn remove_idle(&mut self) {
+        self.sessions.retain(|address, session| {
+            let keep = !session.is_idle();
+            if !keep {
+                println!("central {} disconnected", address);
+            }
+            keep
+        });
+    }
+}
+
+/// Reads writes from one central and forwards complete messages,
+/// noting in `encoding` how they were written.
+async fn read_central(
+    address: Address,
+    mut reader: CharacteristicReader,
+    transmitter: Fanout<FromBle>,
+    encoding: Arc<Mutex<Encoding>>,
+) {
+    let mut read_buf = vec![0; reader.mtu()];
+    let mut reassembler = Reassembler::default();
+    let mut expire_interval = interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            // Drop messages whose fragments stopped coming
+            _ = expire_interval.tick() => {
+                for id in reassembler.expire() {
+                    eprintln!("ble reassembly of message {} from {} timed out", id, address);
+                }
+            }
+
+            read_res = reader.read(&mut read_buf) => {
+                match read_res {
+                    Ok(0) => {
+                        println!("Write stream from {} ended", address);
+                        break;
+                    }
+                    Ok(n) => {
+                        println!("Write request from {} with {} bytes: {:x?}", address, n, &read_buf[0..n]);
+                        // Only complete messages go to the server
+                        match reassembler.push(&read_buf[0..n]) {
+                            Ok(Some(msg)) => {
+                                *encoding.lock().unwrap() = Encoding::of(&msg);
+                                // Waits here if the dispatcher blocks, so the central is read no faster
+                                if transmitter.publish(FromBle { session: address, payload: msg }).await == 0 {
+                                    eprintln!("ble could not transmit, the server is gone");
+                                }
+                            }
+                            Ok(None) => {}
+                            Err(e) => eprintln!("ble fragment from {} dropped: {}", address, e),
+                        }
+                    }
+                    Err(err) => {
+                        println!("Write stream error from {}: {}", address, &err);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,6 +1,368 @@
+//! Fragmentation of messages that do not fit in a single GATT write or
+//! notification.
+//!
+//! Both directions use the same scheme. Every write or notification carries
+//! a 4-byte header followed by a piece of the message:
+//!
+//! ```text
+//! | id: u16 BE | last: 1 bit, seq: 15 bits BE | data ... |
+//! ```
+//!
+//! All fragments of a message share the same `id`, `seq` counts up from 0
+//! and the last fragment has the `last` bit set. A message that fits in one
+//! write is sent as a single fragment with seq 0 and `last` set.
 
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use thiserror::Error;
 
-struct frag {
-    id: u16
+/// Size of the header in front of every fragment.
+pub const FRAG_HEADER_SIZE: usize = 4;
+
+/// Largest message accepted by default.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Messages being reassembled at the same time, by default.
+pub const MAX_PARTIALS: usize = 8;
+
+/// How long a partial message is kept without receiving a fragment.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const LAST_FLAG: u16 = 0x8000;
+const SEQ_MASK: u16 = 0x7fff;
+
+#[derive(Error, Debug)]
+pub enum TransportError {
+    #[error("fragment of {0} bytes is shorter than its header")]
+    TooShort(usize),
+
+    #[error("fragment {seq} of message {id} out of order, expected {expected}")]
+    OutOfOrder { id: u16, seq: u16, expected: u16 },
+
+    #[error("message {id} exceeds max size {max}")]
+    TooLarge { id: u16, max: usize },
+
+    #[error("too many partial messages, max {0}")]
+    TooManyPartials(usize),
+
+    #[error("chunk size {0} leaves no room for data")]
+    ChunkTooSmall(usize),
+}
+
+/// Header of a single fragment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragHeader {
+    pub id: u16,
+    pub seq: u16,
+    pub last: bool,
+}
+
+impl FragHeader {
+    pub fn parse(chunk: &[u8]) -> Result<(Self, &[u8]), TransportError> {
+        if chunk.len() < FRAG_HEADER_SIZE {
+            return Err(TransportError::TooShort(chunk.len()));
+        }
+
+        let id = u16::from_be_bytes([chunk[0], chunk[1]]);
+        let seq = u16::from_be_bytes([chunk[2], chunk[3]]);
+        let header = FragHeader {
+            id,
+            seq: seq & SEQ_MASK,
+            last: seq & LAST_FLAG != 0,
+        };
+
+        Ok((header, &chunk[FRAG_HEADER_SIZE..]))
+    }
+
+    pub fn write(&self, dst: &mut BytesMut) {
+        let mut seq = self.seq & SEQ_MASK;
+        if self.last {
+            seq |= LAST_FLAG;
+        }
+
+        dst.put_u16(self.id);
+        dst.put_u16(seq);
+    }
+}
+
+struct Partial {
+    data: BytesMut,
+    next_seq: u16,
+    updated: Instant,
 }
 
+/// Rebuilds complete messages out of fragments.
+pub struct Reassembler {
+    partials: HashMap<u16, Partial>,
+    max_partials: usize,
+    max_message: usize,
+    timeout: Duration,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new(MAX_PARTIALS, MAX_MESSAGE_SIZE, REASSEMBLY_TIMEOUT)
+    }
+}
+
+impl Reassembler {
+    pub fn new(max_partials: usize, max_message: usize, timeout: Duration) -> Self {
+        Self {
+            partials: HashMap::new(),
+            max_partials,
+            max_message,
+            timeout,
+        }
+    }
+
+    /// Adds a fragment, returns the message once its last fragment arrives.
+    ///
+    /// On error the partial message the fragment belonged to is dropped.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Bytes>, TransportError> {
+        let (header, data) = FragHeader::parse(chunk)?;
+        let now = Instant::now();
+
+        if header.seq == 0 {
+            // A new message, replaces whatever was pending under the same id
+            self.partials.remove(&header.id);
+
+            if header.last {
+                return self.check_size(header.id, data.len()).map(|_| Some(Bytes::copy_from_slice(data)));
+            }
+
+            if self.partials.len() >= self.max_partials {
+                return Err(TransportError::TooManyPartials(self.max_partials));
+            }
+
+            self.check_size(header.id, data.len())?;
+            self.partials.insert(header.id, Partial {
+                data: BytesMut::from(data),
+                next_seq: 1,
+                updated: now,
+            });
+            return Ok(None);
+        }
+
+        let Some(mut partial) = self.partials.remove(&header.id) else {
+            return Err(TransportError::OutOfOrder { id: header.id, seq: header.seq, expected: 0 });
+        };
+
+        if header.seq != partial.next_seq {
+            return Err(TransportError::OutOfOrder {
+                id: header.id,
+                seq: header.seq,
+                expected: partial.next_seq,
+            });
+        }
+
+        self.check_size(header.id, partial.data.len() + data.len())?;
+        partial.data.put_slice(data);
+
+        if header.last {
+            return Ok(Some(partial.data.freeze()));
+        }
+
+        partial.next_seq += 1;
+        partial.updated = now;
+        self.partials.insert(header.id, partial);
+
+        Ok(None)
+    }
+
+    /// Drops partial messages that have not seen a fragment within the
+    /// timeout, returns their ids.
+    pub fn expire(&mut self) -> Vec<u16> {
+        let now = Instant::now();
+        let expired: Vec<u16> = self
+            .partials
+            .iter()
+            .filter(|(_, p)| now.duration_since(p.updated) >= self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &expired {
+            self.partials.remove(id);
+        }
+
+        expired
+    }
+
+    fn check_size(&self, id: u16, len: usize) -> Result<(), TransportError> {
+        if len > self.max_message {
+            return Err(TransportError::TooLarge { id, max: self.max_message });
+        }
+        Ok(())
+    }
+}
+
+/// Splits `msg` into fragments of at most `chunk_size` bytes, header included.
+pub fn fragment(id: u16, msg: &[u8], chunk_size: usize) -> Result<Vec<Bytes>, TransportError> {
+    if chunk_size <= FRAG_HEADER_SIZE {
+        return Err(TransportError::ChunkTooSmall(chunk_size));
+    }
+
+    let data_size = chunk_size - FRAG_HEADER_SIZE;
+    let mut pieces: Vec<&[u8]> = msg.chunks(data_size).collect();
+    if pieces.is_empty() {
+        // Empty messages still take one fragment
+        pieces.push(&[]);
+    }
+
+    let count = pieces.len();
+    if count > SEQ_MASK as usize + 1 {
+        return Err(TransportError::TooLarge { id, max: data_size * (SEQ_MASK as usize + 1) });
+    }
+
+    let mut fragments = Vec::with_capacity(count);
+    for (seq, data) in pieces.into_iter().enumerate() {
+        let header = FragHeader {
+            id,
+            seq: seq as u16,
+            last: seq + 1 == count,
+        };
+
+        let mut chunk = BytesMut::with_capacity(FRAG_HEADER_SIZE + data.len());
+        header.write(&mut chunk);
+        chunk.put_slice(data);
+        fragments.push(chunk.freeze());
+    }
+
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: u16, seq: u16, last: bool, data: &[u8]) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        FragHeader { id, seq, last }.write(&mut buf);
+        buf.put_slice(data);
+        buf.to_vec()
+    }
+
+    #[test]
+    fn single_fragment_message() {
+        let mut reassembler = Reassembler::default();
+        let msg = reassembler.push(&chunk(1, 0, true, b"hello")).unwrap();
+        assert_eq!(msg.as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn interleaved_messages() {
+        let mut reassembler = Reassembler::default();
+
+        assert!(reassembler.push(&chunk(1, 0, false, b"he")).unwrap().is_none());
+        assert!(reassembler.push(&chunk(2, 0, false, b"wo")).unwrap().is_none());
+        assert!(reassembler.push(&chunk(1, 1, false, b"ll")).unwrap().is_none());
+        assert_eq!(reassembler.push(&chunk(2, 1, true, b"rld")).unwrap().as_deref(), Some(&b"world"[..]));
+        assert_eq!(reassembler.push(&chunk(1, 2, true, b"o")).unwrap().as_deref(), Some(&b"hello"[..]));
+    }
+
+    #[test]
+    fn out_of_order_fragment_drops_the_message() {
+        let mut reassembler = Reassembler::default();
+
+        reassembler.push(&chunk(1, 0, false, b"he")).unwrap();
+        let err = reassembler.push(&chunk(1, 2, true, b"o")).unwrap_err();
+        assert!(matches!(err, TransportError::OutOfOrder { id: 1, seq: 2, expected: 1 }));
+
+        // Nothing left to continue
+        let err = reassembler.push(&chunk(1, 1, true, b"ll")).unwrap_err();
+        assert!(matches!(err, TransportError::OutOfOrder { id: 1, seq: 1, expected: 0 }));
+    }
+
+    #[test]
+    fn seq_zero_restarts_a_message() {
+        let mut reassembler = Reassembler::default();
+
+        reassembler.push(&chunk(1, 0, false, b"stale")).unwrap();
+        reassembler.push(&chunk(1, 0, false, b"fr")).unwrap();
+        assert_eq!(reassembler.push(&chunk(1, 1, true, b"esh")).unwrap().as_deref(), Some(&b"fresh"[..]));
+    }
+
+    #[test]
+    fn short_fragment_is_rejected() {
+        let mut reassembler = Reassembler::default();
+        assert!(matches!(reassembler.push(&[0, 1, 0]), Err(TransportError::TooShort(3))));
+    }
+
+    #[test]
+    fn idle_partials_expire() {
+        let mut reassembler = Reassembler::new(MAX_PARTIALS, MAX_MESSAGE_SIZE, Duration::from_millis(20));
+
+        reassembler.push(&chunk(1, 0, false, b"he")).unwrap();
+        assert!(reassembler.expire().is_empty());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(reassembler.expire(), vec![1]);
+        assert!(reassembler.push(&chunk(1, 1, true, b"llo")).is_err());
+    }
+
+    #[test]
+    fn too_many_partials() {
+        let mut reassembler = Reassembler::new(2, MAX_MESSAGE_SIZE, REASSEMBLY_TIMEOUT);
+
+        reassembler.push(&chunk(1, 0, false, b"a")).unwrap();
+        reassembler.push(&chunk(2, 0, false, b"b")).unwrap();
+        assert!(matches!(reassembler.push(&chunk(3, 0, false, b"c")), Err(TransportError::TooManyPartials(2))));
+
+        // Complete single fragments take no slot
+        assert!(reassembler.push(&chunk(3, 0, true, b"c")).unwrap().is_some());
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let mut reassembler = Reassembler::new(MAX_PARTIALS, 4, REASSEMBLY_TIMEOUT);
+
+        assert!(matches!(reassembler.push(&chunk(1, 0, true, b"hello")), Err(TransportError::TooLarge { id: 1, max: 4 })));
+
+        reassembler.push(&chunk(2, 0, false, b"abc")).unwrap();
+        assert!(matches!(reassembler.push(&chunk(2, 1, true, b"de")), Err(TransportError::TooLarge { id: 2, max: 4 })));
+    }
+
+    #[test]
+    fn fragment_round_trip() {
+        let msg: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        // A notification at the default ATT MTU
+        let chunks = fragment(7, &msg, 20).unwrap();
+        assert_eq!(chunks.len(), 1000usize.div_ceil(16));
+        assert!(chunks.iter().all(|c| c.len() <= 20));
+
+        let mut reassembler = Reassembler::default();
+        let (last, rest) = chunks.split_last().unwrap();
+        for c in rest {
+            assert!(reassembler.push(c).unwrap().is_none());
+        }
+        assert_eq!(reassembler.push(last).unwrap().as_deref(), Some(&msg[..]));
+    }
+
+    #[test]
+    fn fragment_headers() {
+        let chunks = fragment(3, b"abcdefgh", FRAG_HEADER_SIZE + 3).unwrap();
+        let headers: Vec<FragHeader> = chunks.iter().map(|c| FragHeader::parse(c).unwrap().0).collect();
+
+        assert_eq!(headers, vec![
+            FragHeader { id: 3, seq: 0, last: false },
+            FragHeader { id: 3, seq: 1, last: false },
+            FragHeader { id: 3, seq: 2, last: true },
+        ]);
+    }
+
+    #[test]
+    fn empty_message_takes_one_fragment() {
+        let chunks = fragment(1, b"", 20).unwrap();
+        assert_eq!(chunks, vec![Bytes::from_static(&[0, 1, 0x80, 0])]);
+    }
+
+    #[test]
+    fn fragment_limits() {
+        assert!(matches!(fragment(1, b"abc", FRAG_HEADER_SIZE), Err(TransportError::ChunkTooSmall(4))));
+
+        // One byte per fragment runs out of sequence numbers
+        let msg = vec![0u8; SEQ_MASK as usize + 2];
+        assert!(matches!(fragment(1, &msg, FRAG_HEADER_SIZE + 1), Err(TransportError::TooLarge { id: 1, .. })));
+    }
+}
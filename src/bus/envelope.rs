@@ -0,0 +1,52 @@
+//! Messages exchanged between the server and the BLE adapter, and between
+//! the server and its socket clients.
+
+use bluer::Address;
+use bytes::Bytes;
+use std::sync::Arc;
+
+use crate::proto::msg::Message;
+
+/// Which BLE centrals a message is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// Every connected central.
+    Broadcast,
+    /// Only the central with this address.
+    Central(Address),
+}
+
+/// A message from the server to the BLE side, encoded there for each
+/// central the way it speaks.
+#[derive(Clone)]
+pub struct ToBle {
+    pub route: Route,
+    pub message: Arc<Message>,
+}
+
+impl ToBle {
+    pub fn broadcast(message: Message) -> Self {
+        Self { route: Route::Broadcast, message: Arc::new(message) }
+    }
+
+    pub fn central(address: Address, message: Message) -> Self {
+        Self { route: Route::Central(address), message: Arc::new(message) }
+    }
+}
+
+/// A message from a BLE central to the server.
+#[derive(Debug, Clone)]
+pub struct FromBle {
+    /// Session handle of the central that sent it.
+    pub session: Address,
+    pub payload: Bytes,
+}
+
+/// A message from BLE on its way to socket clients, decoded once for all
+/// of them.
+#[derive(Clone)]
+pub struct ToClient {
+    /// As received, for messages that did not decode.
+    pub raw: Bytes,
+    pub message: Option<Arc<Message>>,
+}
@@ -0,0 +1,455 @@
+//! A broadcast channel where every subscriber picks what happens when it
+//! falls behind.
+//!
+//! `tokio::sync::broadcast` always drops the oldest messages of a slow
+//! receiver. Here each subscriber has its own bounded queue and a
+//! `Backpressure` policy applied when that queue is full. A subscriber may
+//! also pass a filter, messages it rejects never take room in its queue.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// What a publisher does when a subscriber's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait until the subscriber makes room.
+    Block,
+    /// Drop the oldest queued message, the subscriber is told how many it missed.
+    DropOldest,
+    /// Cut the subscriber off, until it reconnects.
+    Disconnect,
+}
+
+impl fmt::Display for Backpressure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backpressure::Block => write!(f, "block"),
+            Backpressure::DropOldest => write!(f, "drop-oldest"),
+            Backpressure::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+impl FromStr for Backpressure {
+    type Err = String;
+
+    /// The names `Display` writes.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(Backpressure::Block),
+            "drop-oldest" => Ok(Backpressure::DropOldest),
+            "disconnect" => Ok(Backpressure::Disconnect),
+            _ => Err("expected block, drop-oldest or disconnect".to_string()),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RecvError {
+    #[error("lagged behind, {0} messages dropped")]
+    Lagged(u64),
+
+    #[error("disconnected for being too slow")]
+    Disconnected,
+
+    #[error("channel closed")]
+    Closed,
+}
+
+/// Counters for every subscriber of a `Fanout`.
+#[derive(Debug, Default)]
+pub struct FanoutStats {
+    published: AtomicU64,
+    dropped: AtomicU64,
+    disconnected: AtomicU64,
+    blocked: AtomicU64,
+}
+
+impl FanoutStats {
+    /// Messages published.
+    pub fn published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped from `DropOldest` subscribers.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// `Disconnect` subscribers cut off.
+    pub fn disconnected(&self) -> u64 {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+
+    /// Times a publisher had to wait on a `Block` subscriber.
+    pub fn blocked(&self) -> u64 {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    /// Dropped since the subscriber was last told.
+    lagged: u64,
+    disconnected: bool,
+    /// No more messages will come, either side went away.
+    closed: bool,
+}
+
+/// Decides which messages a subscriber gets.
+pub type Filter<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+
+struct Queue<T> {
+    state: Mutex<QueueState<T>>,
+    capacity: usize,
+    policy: Backpressure,
+    filter: Option<Filter<T>>,
+    /// Wakes the subscriber.
+    readable: Notify,
+    /// Wakes publishers blocked on this queue.
+    writable: Notify,
+}
+
+struct Inner<T> {
+    subscribers: Mutex<Vec<Weak<Queue<T>>>>,
+    stats: Arc<FanoutStats>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // Last publisher is gone, subscribers end once they drain their queue
+        for queue in self.subscribers.get_mut().unwrap().iter().filter_map(Weak::upgrade) {
+            queue.state.lock().unwrap().closed = true;
+            queue.readable.notify_one();
+        }
+    }
+}
+
+/// Publishing side, cheap to clone. The channel closes when the last clone
+/// is dropped.
+pub struct Fanout<T> {
+    inner: Arc<Inner<T>>,
+}
+
+impl<T> Clone for Fanout<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+/// A handle that can subscribe without keeping the channel open.
+pub struct WeakFanout<T> {
+    inner: Weak<Inner<T>>,
+}
+
+impl<T> Clone for WeakFanout<T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<T> WeakFanout<T> {
+    pub fn upgrade(&self) -> Option<Fanout<T>> {
+        self.inner.upgrade().map(|inner| Fanout { inner })
+    }
+}
+
+impl<T: Clone> Default for Fanout<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone> Fanout<T> {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                subscribers: Mutex::new(Vec::new()),
+                stats: Arc::new(FanoutStats::default()),
+            }),
+        }
+    }
+
+    pub fn downgrade(&self) -> WeakFanout<T> {
+        WeakFanout { inner: Arc::downgrade(&self.inner) }
+    }
+
+    pub fn stats(&self) -> Arc<FanoutStats> {
+        self.inner.stats.clone()
+    }
+
+    /// Adds a subscriber holding up to `capacity` messages.
+    pub fn subscribe(&self, capacity: usize, policy: Backpressure) -> Subscriber<T> {
+        self.subscribe_with(capacity, policy, None)
+    }
+
+    /// Adds a subscriber that only gets the messages `filter` accepts.
+    /// The filter runs on the publisher's task, it must not block.
+    pub fn subscribe_filtered(&self, capacity: usize, policy: Backpressure, filter: Filter<T>) -> Subscriber<T> {
+        self.subscribe_with(capacity, policy, Some(filter))
+    }
+
+    fn subscribe_with(&self, capacity: usize, policy: Backpressure, filter: Option<Filter<T>>) -> Subscriber<T> {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity),
+                lagged: 0,
+                disconnected: false,
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            policy,
+            filter,
+            readable: Notify::new(),
+            writable: Notify::new(),
+        });
+
+        self.inner.subscribers.lock().unwrap().push(Arc::downgrade(&queue));
+
+        Subscriber { queue }
+    }
+
+    /// Sends `item` to every subscriber whose filter accepts it, returns how
+    /// many got it. Only waits if a `Block` subscriber is full.
+    pub async fn publish(&self, item: T) -> usize {
+        let queues: Vec<Arc<Queue<T>>> = {
+            let mut subscribers = self.inner.subscribers.lock().unwrap();
+            subscribers.retain(|q| q.strong_count() > 0);
+            subscribers.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        let stats = &self.inner.stats;
+        stats.published.fetch_add(1, Ordering::Relaxed);
+
+        let mut delivered = 0;
+        for queue in queues {
+            if queue.filter.as_ref().is_some_and(|accepts| !accepts(&item)) {
+                continue;
+            }
+
+            if self.push(&queue, item.clone()).await {
+                delivered += 1;
+            }
+        }
+
+        delivered
+    }
+
+    async fn push(&self, queue: &Queue<T>, item: T) -> bool {
+        let stats = &self.inner.stats;
+        let mut waited = false;
+
+        loop {
+            // Registered before checking, so a recv in between is not missed
+            let writable = queue.writable.notified();
+
+            {
+                let mut state = queue.state.lock().unwrap();
+                if state.closed || state.disconnected {
+                    return false;
+                }
+
+                if state.items.len() < queue.capacity {
+                    state.items.push_back(item);
+                    queue.readable.notify_one();
+                    return true;
+                }
+
+                match queue.policy {
+                    Backpressure::Block => {}
+                    Backpressure::DropOldest => {
+                        state.items.pop_front();
+                        state.items.push_back(item);
+                        state.lagged += 1;
+                        stats.dropped.fetch_add(1, Ordering::Relaxed);
+                        queue.readable.notify_one();
+                        return true;
+                    }
+                    Backpressure::Disconnect => {
+                        state.disconnected = true;
+                        state.items.clear();
+                        stats.disconnected.fetch_add(1, Ordering::Relaxed);
+                        queue.readable.notify_one();
+                        return false;
+                    }
+                }
+            }
+
+            if !waited {
+                waited = true;
+                stats.blocked.fetch_add(1, Ordering::Relaxed);
+            }
+            writable.await;
+        }
+    }
+}
+
+/// Receiving side of a `Fanout`.
+pub struct Subscriber<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> Subscriber<T> {
+    pub fn policy(&self) -> Backpressure {
+        self.queue.policy
+    }
+
+    /// Waits for the next message.
+    ///
+    /// After messages were dropped, returns `RecvError::Lagged` once
+    /// before carrying on with the messages still queued.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        loop {
+            let readable = self.queue.readable.notified();
+
+            {
+                let mut state = self.queue.state.lock().unwrap();
+
+                if state.disconnected {
+                    return Err(RecvError::Disconnected);
+                }
+
+                if state.lagged > 0 {
+                    let lagged = state.lagged;
+                    state.lagged = 0;
+                    return Err(RecvError::Lagged(lagged));
+                }
+
+                if let Some(item) = state.items.pop_front() {
+                    self.queue.writable.notify_waiters();
+                    return Ok(item);
+                }
+
+                if state.closed {
+                    return Err(RecvError::Closed);
+                }
+            }
+
+            readable.await;
+        }
+    }
+
+    /// Takes messages again after `RecvError::Disconnected`, starting with
+    /// the next one published. What was queued before is gone.
+    pub fn reconnect(&mut self) {
+        self.queue.state.lock().unwrap().disconnected = false;
+    }
+}
+
+impl<T> Drop for Subscriber<T> {
+    fn drop(&mut self) {
+        // Let blocked publishers move on
+        let mut state = self.queue.state.lock().unwrap();
+        state.closed = true;
+        state.items.clear();
+        drop(state);
+        self.queue.writable.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const SHORT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn block_waits_for_recv() {
+        let fanout = Fanout::new();
+        let mut subs = fanout.subscribe(1, Backpressure::Block);
+        assert_eq!(fanout.publish(1).await, 1);
+
+        let publisher = fanout.clone();
+        let mut blocked = tokio::spawn(async move { publisher.publish(2).await });
+        assert!(timeout(SHORT, &mut blocked).await.is_err());
+        assert_eq!(fanout.stats().blocked(), 1);
+
+        assert_eq!(subs.recv().await, Ok(1));
+        assert_eq!(blocked.await.unwrap(), 1);
+        assert_eq!(subs.recv().await, Ok(2));
+    }
+
+    #[tokio::test]
+    async fn block_gives_up_on_dropped_subscribers() {
+        let fanout = Fanout::new();
+        let subs = fanout.subscribe(1, Backpressure::Block);
+        fanout.publish(1).await;
+
+        let publisher = fanout.clone();
+        let mut blocked = tokio::spawn(async move { publisher.publish(2).await });
+        assert!(timeout(SHORT, &mut blocked).await.is_err());
+
+        drop(subs);
+        assert_eq!(blocked.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_reports_the_lag_once() {
+        let fanout = Fanout::new();
+        let mut subs = fanout.subscribe(2, Backpressure::DropOldest);
+        for i in 1..=5 {
+            assert_eq!(fanout.publish(i).await, 1);
+        }
+
+        assert_eq!(subs.recv().await, Err(RecvError::Lagged(3)));
+        assert_eq!(subs.recv().await, Ok(4));
+        assert_eq!(subs.recv().await, Ok(5));
+        assert!(timeout(SHORT, subs.recv()).await.is_err());
+        assert_eq!(fanout.stats().dropped(), 3);
+    }
+
+    #[tokio::test]
+    async fn disconnect_cuts_off_until_reconnect() {
+        let fanout = Fanout::new();
+        let mut subs = fanout.subscribe(1, Backpressure::Disconnect);
+        assert_eq!(fanout.publish(1).await, 1);
+        assert_eq!(fanout.publish(2).await, 0);
+        assert_eq!(fanout.publish(3).await, 0);
+
+        assert_eq!(subs.recv().await, Err(RecvError::Disconnected));
+        assert_eq!(subs.recv().await, Err(RecvError::Disconnected));
+        assert_eq!(fanout.stats().disconnected(), 1);
+
+        subs.reconnect();
+        assert_eq!(fanout.publish(4).await, 1);
+        assert_eq!(subs.recv().await, Ok(4));
+    }
+
+    #[tokio::test]
+    async fn filtered_messages_take_no_room() {
+        let fanout = Fanout::new();
+        let mut subs = fanout.subscribe_filtered(1, Backpressure::DropOldest, Box::new(|n: &i32| n % 2 == 0));
+
+        assert_eq!(fanout.publish(2).await, 1);
+        assert_eq!(fanout.publish(3).await, 0);
+        assert_eq!(fanout.publish(5).await, 0);
+
+        assert_eq!(subs.recv().await, Ok(2));
+        assert_eq!(fanout.stats().dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn subscribers_close_after_the_last_fanout() {
+        let fanout = Fanout::new();
+        let weak = fanout.downgrade();
+        let mut subs = fanout.subscribe(4, Backpressure::Block);
+        let other = fanout.clone();
+
+        fanout.publish(1).await;
+        drop(fanout);
+        other.publish(2).await;
+        drop(other);
+        assert!(weak.upgrade().is_none());
+
+        assert_eq!(subs.recv().await, Ok(1));
+        assert_eq!(subs.recv().await, Ok(2));
+        assert_eq!(subs.recv().await, Err(RecvError::Closed));
+    }
+}
@@ -0,0 +1,5 @@
+pub mod envelope;
+pub mod fanout;
+
+pub use envelope::{FromBle, Route, ToBle, ToClient};
+pub use fanout::{Backpressure, Fanout, Subscriber};
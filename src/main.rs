@@ -1,11 +1,12 @@
 //! Entrypoint that delegates BLE work to the `ble` module.
 
 pub mod ble;
+pub mod bus;
 pub mod server;
 pub mod proto;
 
-use bytes::Bytes;
-use tokio::sync::broadcast;
+use crate::bus::{Fanout, FromBle, ToBle};
+use crate::server::ServerConfig;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> std::io::Result<()> {
@@ -15,16 +16,18 @@ async fn main() -> std::io::Result<()> {
     //let to_ble = PeerPair::<Bytes>::new(16);
     //let to_server = PeerPair::<Bytes>::new(16);
 
+    let config = ServerConfig::from_env().map_err(std::io::Error::other)?;
+
     // Broadcast channels 
-    let (server_broadcaster, _) = broadcast::channel::<Bytes>(16);
-    let (ble_broadacaster, _) = broadcast::channel::<Bytes>(16);
+    let server_broadcaster = Fanout::<ToBle>::new();
+    let ble_broadacaster = Fanout::<FromBle>::new();
 
-    // The server will get a subscription for each spawned connect task
-    // out of the ble broadcaster.
-    let server_provider = ble_broadacaster.clone();
+    // The server's dispatcher takes everything BLE sends, and hands it on
+    // to each connection.
+    let from_ble = ble_broadacaster.subscribe(config.dispatch_queue, config.dispatch_backpressure);
 
     // Subscription to server notifications for BLE.
-    let ble_subs = server_broadcaster.subscribe();
+    let ble_subs = server_broadcaster.subscribe(config.ble_queue, config.ble_backpressure);
 
     tokio::spawn(async move {
         if let Err(err) = ble::configure(ble_subs, ble_broadacaster).await {
@@ -32,7 +35,7 @@ async fn main() -> std::io::Result<()> {
         }
     });        // BLE task
 
-    server::run(server_provider, server_broadcaster).await?;       // or spawn as well
+    server::run(config, from_ble, server_broadcaster).await?;       // or spawn as well
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,410 @@
+//! Compact binary encoding of SMSG messages, for links where every byte
+//! counts such as BLE notifications.
+//!
+//! The start line is packed and the body is CBOR instead of JSON text:
+//!
+//! ```text
+//! | marker u8 | major u8 | minor u8 |
+//! request:  | id u32 BE | action u8 [str8 if 0] | kind u8 [str8 if 0] |
+//! response: | id u32 BE | code u16 BE | reason str8 |
+//! event:    | source str8 | kind u8 [str8 if 0] | name str8 |
+//! | header count u8 | (name str8, value str16) ... | CBOR body, if any |
+//! ```
+//!
+//! `str8` and `str16` are UTF-8 strings behind a u8 or u16 BE length. The
+//! marker byte is never valid ASCII, so binary and text messages can be told
+//! apart by their first byte.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde_json::Value;
+use std::fmt;
+
+use crate::proto::headers::Headers;
+use crate::proto::msg::{Event, Message, MessageError, Request, Response, PROTO_NAME};
+use crate::proto::types::{Action, Kind};
+use crate::proto::version::Version;
+
+const MARKER_REQUEST: u8 = 0xb1;
+const MARKER_RESPONSE: u8 = 0xb2;
+const MARKER_EVENT: u8 = 0xb3;
+
+/// Action and kind codes, 0 means the name follows as a string.
+const CODE_OTHER: u8 = 0;
+
+/// How messages are written on a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// Start line and headers in ASCII, JSON body.
+    #[default]
+    Text,
+    /// Packed start line and headers, CBOR body.
+    Binary,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Text => "text",
+            Encoding::Binary => "binary",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(Encoding::Text),
+            "binary" => Some(Encoding::Binary),
+            _ => None,
+        }
+    }
+
+    /// Encoding `payload` was written in, judging by its first byte.
+    pub fn of(payload: &[u8]) -> Self {
+        match payload.first() {
+            Some(&MARKER_REQUEST) | Some(&MARKER_RESPONSE) | Some(&MARKER_EVENT) => Encoding::Binary,
+            _ => Encoding::Text,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn action_code(action: &Action) -> u8 {
+    match action {
+        Action::Get => 1,
+        Action::Set => 2,
+        Action::Hello => 3,
+        Action::Subscribe => 4,
+        Action::Unsubscribe => 5,
+        Action::Other(_) => CODE_OTHER,
+    }
+}
+
+fn action_from_code(code: u8) -> Option<Action> {
+    match code {
+        1 => Some(Action::Get),
+        2 => Some(Action::Set),
+        3 => Some(Action::Hello),
+        4 => Some(Action::Subscribe),
+        5 => Some(Action::Unsubscribe),
+        _ => None,
+    }
+}
+
+fn kind_code(kind: &Kind) -> u8 {
+    match kind {
+        Kind::Metrics => 1,
+        Kind::Gateway => 2,
+        Kind::Events => 3,
+        Kind::Topics => 4,
+        Kind::Other(_) => CODE_OTHER,
+    }
+}
+
+fn kind_from_code(code: u8) -> Option<Kind> {
+    match code {
+        1 => Some(Kind::Metrics),
+        2 => Some(Kind::Gateway),
+        3 => Some(Kind::Events),
+        4 => Some(Kind::Topics),
+        _ => None,
+    }
+}
+
+fn malformed(what: impl Into<String>) -> MessageError {
+    MessageError::MalformedBinary(what.into())
+}
+
+struct Writer {
+    buf: BytesMut,
+}
+
+impl Writer {
+    fn str8(&mut self, s: &str) -> Result<(), MessageError> {
+        let len = u8::try_from(s.len()).map_err(|_| malformed(format!("{:?} longer than 255 bytes", s)))?;
+        self.buf.put_u8(len);
+        self.buf.put_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn str16(&mut self, s: &str) -> Result<(), MessageError> {
+        let len = u16::try_from(s.len()).map_err(|_| malformed("header value longer than 64K"))?;
+        self.buf.put_u16(len);
+        self.buf.put_slice(s.as_bytes());
+        Ok(())
+    }
+
+    fn prelude(&mut self, marker: u8, version: &str) -> Result<(), MessageError> {
+        let version = version
+            .parse::<Version>()
+            .map_err(|_| MessageError::UnsupportedVersion(format!("{}/{}", PROTO_NAME, version)))?;
+        let major = u8::try_from(version.major).map_err(|_| malformed("major version above 255"))?;
+        let minor = u8::try_from(version.minor).map_err(|_| malformed("minor version above 255"))?;
+
+        self.buf.put_u8(marker);
+        self.buf.put_u8(major);
+        self.buf.put_u8(minor);
+        Ok(())
+    }
+
+    fn id(&mut self, id: usize) -> Result<(), MessageError> {
+        let id = u32::try_from(id).map_err(|_| malformed(format!("id {} does not fit in 32 bits", id)))?;
+        self.buf.put_u32(id);
+        Ok(())
+    }
+
+    fn coded(&mut self, code: u8, name: &str) -> Result<(), MessageError> {
+        self.buf.put_u8(code);
+        if code == CODE_OTHER {
+            self.str8(name)?;
+        }
+        Ok(())
+    }
+
+    fn trailer(mut self, headers: &Headers, body: Option<&Value>) -> Result<Bytes, MessageError> {
+        let count = u8::try_from(headers.len()).map_err(|_| malformed("more than 255 headers"))?;
+        self.buf.put_u8(count);
+        for (name, value) in headers.iter() {
+            self.str8(name)?;
+            self.str16(value)?;
+        }
+
+        if let Some(body) = body {
+            let mut writer = (&mut self.buf).writer();
+            ciborium::into_writer(body, &mut writer).map_err(|e| MessageError::Cbor(e.to_string()))?;
+        }
+
+        Ok(self.buf.freeze())
+    }
+}
+
+pub fn encode_request(req: &Request) -> Result<Bytes, MessageError> {
+    let mut w = Writer { buf: BytesMut::new() };
+
+    w.prelude(MARKER_REQUEST, &req.version)?;
+    w.id(req.id)?;
+    w.coded(action_code(&req.action), req.action.as_str())?;
+    w.coded(kind_code(&req.kind), req.kind.as_str())?;
+
+    w.trailer(&req.headers, req.body.as_ref())
+}
+
+pub fn encode_response(resp: &Response) -> Result<Bytes, MessageError> {
+    let mut w = Writer { buf: BytesMut::new() };
+
+    w.prelude(MARKER_RESPONSE, &resp.version)?;
+    w.id(resp.id)?;
+    let code = u16::try_from(resp.code).map_err(|_| malformed(format!("code {} does not fit in 16 bits", resp.code)))?;
+    w.buf.put_u16(code);
+    w.str8(&resp.text)?;
+
+    w.trailer(&resp.headers, resp.body.as_ref())
+}
+
+pub fn encode_event(event: &Event) -> Result<Bytes, MessageError> {
+    let mut w = Writer { buf: BytesMut::new() };
+
+    w.prelude(MARKER_EVENT, &event.version)?;
+    w.str8(&event.source)?;
+    w.coded(kind_code(&event.kind), event.kind.as_str())?;
+    w.str8(&event.name)?;
+
+    w.trailer(&event.headers, event.body.as_ref())
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize, what: &str) -> Result<&'a [u8], MessageError> {
+        if self.buf.len() < n {
+            return Err(malformed(format!("truncated {}", what)));
+        }
+
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self, what: &str) -> Result<u8, MessageError> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> Result<u16, MessageError> {
+        let b = self.take(2, what)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self, what: &str) -> Result<u32, MessageError> {
+        let b = self.take(4, what)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn str(&mut self, len: usize, what: &str) -> Result<&'a str, MessageError> {
+        Ok(std::str::from_utf8(self.take(len, what)?)?)
+    }
+
+    fn str8(&mut self, what: &str) -> Result<&'a str, MessageError> {
+        let len = self.u8(what)? as usize;
+        self.str(len, what)
+    }
+
+    fn str16(&mut self, what: &str) -> Result<&'a str, MessageError> {
+        let len = self.u16(what)? as usize;
+        self.str(len, what)
+    }
+
+    fn coded<T: for<'s> From<&'s str>>(&mut self, what: &str, from_code: fn(u8) -> Option<T>) -> Result<T, MessageError> {
+        match self.u8(what)? {
+            CODE_OTHER => Ok(T::from(self.str8(what)?)),
+            code => from_code(code).ok_or_else(|| malformed(format!("unknown {} code {}", what, code))),
+        }
+    }
+}
+
+/// Decodes a message in the binary encoding.
+pub fn decode(payload: &[u8]) -> Result<Message, MessageError> {
+    let mut r = Reader { buf: payload };
+
+    let marker = r.u8("marker")?;
+    let version = Version::new(r.u8("version")? as u16, r.u8("version")? as u16);
+    if !version.is_supported() {
+        return Err(MessageError::UnsupportedVersion(format!("{}/{}", PROTO_NAME, version)));
+    }
+
+    let protocol = PROTO_NAME.to_string();
+    let version = version.to_string();
+
+    let message = match marker {
+        MARKER_REQUEST => {
+            let id = r.u32("id")? as usize;
+            let action = r.coded("action", action_from_code)?;
+            let kind = r.coded("kind", kind_from_code)?;
+            let (headers, body) = trailer(&mut r)?;
+
+            Message::Request(Request { protocol, version, id, action, kind, headers, body })
+        }
+        MARKER_RESPONSE => {
+            let id = r.u32("id")? as usize;
+            let code = r.u16("code")? as usize;
+            let text = r.str8("reason")?.to_string();
+            let (headers, body) = trailer(&mut r)?;
+
+            Message::Response(Response { protocol, version, id, code, text, headers, body })
+        }
+        MARKER_EVENT => {
+            let source = r.str8("source")?.to_string();
+            let kind = r.coded("kind", kind_from_code)?;
+            let name = r.str8("name")?.to_string();
+            let (headers, body) = trailer(&mut r)?;
+
+            Message::Event(Event { protocol, version, source, kind, name, headers, body })
+        }
+        other => return Err(malformed(format!("unknown marker {:#04x}", other))),
+    };
+
+    Ok(message)
+}
+
+fn trailer(r: &mut Reader) -> Result<(Headers, Option<Value>), MessageError> {
+    let mut headers = Headers::new();
+    for _ in 0..r.u8("header count")? {
+        let name = r.str8("header name")?;
+        let value = r.str16("header value")?;
+        headers.insert(name, value);
+    }
+
+    let body = if r.buf.is_empty() {
+        None
+    } else {
+        Some(ciborium::from_reader::<Value, _>(r.buf).map_err(|e| MessageError::Cbor(e.to_string()))?)
+    };
+
+    Ok((headers, body))
+}
+
+/// Id of a binary message that failed to decode, if it got that far.
+pub fn recover_id(payload: &[u8]) -> Option<usize> {
+    if payload.first() == Some(&MARKER_EVENT) {
+        return None;
+    }
+
+    let b = payload.get(3..7)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::msg::{decode_message, PROTO_NUMBER};
+    use serde_json::json;
+
+    fn request(action: Action, kind: Kind) -> Request {
+        Request::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), 70000, action, kind, Some(json!({ "on": true, "level": [1, 2.5] })))
+            .with_header("Correlation-Id", "abc")
+    }
+
+    #[test]
+    fn request_round_trip() {
+        for req in [request(Action::Set, Kind::Metrics), request(Action::Other("RESET".to_string()), Kind::from("living room"))] {
+            let encoded = encode_request(&req).unwrap();
+            assert_eq!(Encoding::of(&encoded), Encoding::Binary);
+
+            let Ok(Message::Request(decoded)) = decode_message(encoded) else {
+                panic!("binary request did not decode");
+            };
+
+            assert_eq!(decoded.id, req.id);
+            assert_eq!(decoded.version, req.version);
+            assert_eq!(decoded.action, req.action);
+            assert_eq!(decoded.kind, req.kind);
+            assert_eq!(decoded.headers, req.headers);
+            assert_eq!(decoded.body, req.body);
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let resp = Response::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), 7, 404, "Not Found".to_string(), None);
+        let encoded = encode_response(&resp).unwrap();
+
+        // Marker, version, id, code, reason and an empty header count
+        assert_eq!(encoded.len(), 1 + 2 + 4 + 2 + 1 + resp.text.len() + 1);
+
+        let Ok(Message::Response(decoded)) = decode(&encoded) else {
+            panic!("binary response did not decode");
+        };
+
+        assert_eq!(decoded.id, 7);
+        assert_eq!(decoded.code, 404);
+        assert_eq!(decoded.text, "Not Found");
+        assert_eq!(decoded.body, None);
+    }
+
+    #[test]
+    fn event_round_trip() {
+        let event = Event::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), "door-3".to_string(), Kind::from("lock"), "forced open".to_string(), None);
+
+        let Ok(Message::Event(decoded)) = decode(&encode_event(&event).unwrap()) else {
+            panic!("binary event did not decode");
+        };
+
+        assert_eq!(decoded.source, "door-3");
+        assert_eq!(decoded.kind, event.kind);
+        assert_eq!(decoded.name, "forced open");
+    }
+
+    #[test]
+    fn truncated_messages_are_malformed() {
+        let encoded = encode_request(&request(Action::Get, Kind::Gateway)).unwrap();
+
+        for len in [1, 5, 8] {
+            assert!(matches!(decode(&encoded[..len]), Err(MessageError::MalformedBinary(_))));
+        }
+        assert_eq!(recover_id(&encoded[..8]), Some(70000));
+    }
+}
@@ -0,0 +1,72 @@
+//! Bodies of the routes the gateway answers itself.
+
+use serde::{Deserialize, Serialize};
+
+/// Body of routes that take none, anything else in it is rejected.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Empty {}
+
+/// Response body of `GET gateway`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GatewayInfo {
+    pub name: String,
+    pub version: String,
+}
+
+/// Body of `HELLO gateway`: the versions and encodings the client speaks,
+/// encodings in order of preference.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello {
+    pub versions: Vec<String>,
+    #[serde(default)]
+    pub encodings: Vec<String>,
+}
+
+/// Response body of `HELLO gateway`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HelloAccepted {
+    /// Version the connection uses from now on.
+    pub version: String,
+    pub min: String,
+    pub max: String,
+    /// Encoding the gateway writes to the connection from now on.
+    pub encoding: String,
+}
+
+/// Body of `SUBSCRIBE events` and `UNSUBSCRIBE events`, a shorthand for
+/// topics on events only. Event names or kinds, `*` as a name stands for
+/// every event. Unsubscribing with an empty body drops every subscription.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventTopics {
+    #[serde(default)]
+    pub names: Vec<String>,
+    #[serde(default)]
+    pub kinds: Vec<String>,
+}
+
+/// A message matches a topic when every field given matches it. `*`
+/// matches any value, but `action` still only matches requests and `event`
+/// only events. `source` is the device or central a message came from.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topic {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Body of `SUBSCRIBE topics` and `UNSUBSCRIBE topics`, and of the response
+/// to both. Unsubscribing with no topics drops every subscription.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topics {
+    #[serde(default)]
+    pub topics: Vec<Topic>,
+}
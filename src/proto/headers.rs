@@ -0,0 +1,84 @@
+//! `Key: Value` header lines between the start line and the body.
+//!
+//! Names compare case-insensitively, like HTTP, but keep the case and the
+//! order they were inserted with so `encode` writes them back as received.
+
+use std::fmt;
+
+pub const CONTENT_TYPE: &str = "Content-Type";
+/// How long the sender waits for a response, in milliseconds.
+pub const TIMEOUT: &str = "Timeout";
+/// Opaque value echoed back in the response.
+pub const CORRELATION_ID: &str = "Correlation-Id";
+pub const SOURCE: &str = "Source";
+pub const DESTINATION: &str = "Destination";
+pub const AUTH_TOKEN: &str = "Auth-Token";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Sets `name`, replacing its previous value in place.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        match self.entries.iter_mut().find(|(k, _)| k.eq_ignore_ascii_case(name)) {
+            Some(entry) => entry.1 = value.to_string(),
+            None => self.entries.push((name.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<String> {
+        let pos = self.entries.iter().position(|(k, _)| k.eq_ignore_ascii_case(name))?;
+        Some(self.entries.remove(pos).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Parses a single header line, `None` if it is not one.
+    pub fn parse_line(line: &str) -> Option<(&str, &str)> {
+        let (name, value) = line.split_once(':')?;
+
+        let valid = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return None;
+        }
+
+        Some((name, value.trim()))
+    }
+}
+
+/// One `Key: Value\n` line per header.
+impl fmt::Display for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in self.iter() {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
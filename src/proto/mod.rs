@@ -1,3 +1,12 @@
+pub mod binary;
+pub mod bodies;
+pub mod headers;
 pub mod msg;
+pub mod types;
+pub mod version;
 
-pub use msg::decode_message;
\ No newline at end of file
+pub use binary::Encoding;
+pub use headers::Headers;
+pub use msg::decode_message;
+pub use types::{Action, Kind};
+pub use version::Version;
\ No newline at end of file
@@ -1,11 +1,20 @@
-use serde_json::Value;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
 use bytes::{Bytes};
 use std::{fmt};
 use thiserror::Error;
 
+use crate::proto::binary::{self, Encoding};
+use crate::proto::headers::{Headers, CORRELATION_ID};
+use crate::proto::types::{Action, Kind};
+use crate::proto::version::{Version, MAX_VERSION, MIN_VERSION};
 
-const PROTO_VERSION: &str  = "SMSG/0.1";
-const START_LINE_ITEMS: usize = 4;
+
+pub const PROTO_NAME: &str = "SMSG";
+/// First token of the start line of an event.
+pub const EVENT_TOKEN: &str = "EVENT";
+/// `MAX_VERSION`, what the gateway speaks unless a client negotiated otherwise.
+pub const PROTO_NUMBER: &str = "0.1";
 
 #[derive(Error, Debug)]
 pub enum MessageError {
@@ -18,60 +27,160 @@ pub enum MessageError {
     #[error("malformed start line: {0}")]
     MalformedStartLine(String),
 
+    #[error("malformed header line: {0}")]
+    MalformedHeader(String),
+
     #[error("received bytes are not UTF8: {0}")]
     ParseUtf8(#[from] std::str::Utf8Error),
 
     #[error("json decode error: {0}")]
     DecodeJson(#[from] serde_json::Error),
-}
 
-enum RequestTokenIndex {
-    IdIndex = 0,
-    ActionIndex,
-    KindIndex,
-    ProtoIndex
+    #[error("invalid body: {0}")]
+    InvalidBody(String),
+
+    #[error("unsupported protocol version: {0}")]
+    UnsupportedVersion(String),
+
+    #[error("malformed binary message: {0}")]
+    MalformedBinary(String),
+
+    #[error("cbor error: {0}")]
+    Cbor(String),
 }
 
-enum ResponseTokenIndex {
-    ProtoIndex = 0,
-    IdIndex,
-    CodeIndex,
-    TextIndex
+impl MessageError {
+    /// Status code of the response telling the sender about this error.
+    pub fn code(&self) -> usize {
+        match self {
+            MessageError::Custom(_) => 400,
+            MessageError::NoStartLine(_) => 400,
+            MessageError::MalformedStartLine(_) => 400,
+            MessageError::MalformedHeader(_) => 400,
+            MessageError::ParseUtf8(_) => 415,
+            MessageError::DecodeJson(_) => 400,
+            MessageError::InvalidBody(_) => 422,
+            MessageError::UnsupportedVersion(_) => 505,
+            MessageError::MalformedBinary(_) => 400,
+            MessageError::Cbor(_) => 400,
+        }
+    }
+
+    /// Reason text of the response telling the sender about this error.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            MessageError::Custom(_) => "Bad Request",
+            MessageError::NoStartLine(_) => "No Start Line",
+            MessageError::MalformedStartLine(_) => "Malformed Start Line",
+            MessageError::MalformedHeader(_) => "Malformed Header",
+            MessageError::ParseUtf8(_) => "Not UTF-8",
+            MessageError::DecodeJson(_) => "Bad JSON",
+            MessageError::InvalidBody(_) => "Invalid Body",
+            MessageError::UnsupportedVersion(_) => "Version Not Supported",
+            MessageError::MalformedBinary(_) => "Malformed Binary",
+            MessageError::Cbor(_) => "Bad CBOR",
+        }
+    }
+
+    /// Machine readable name of the variant, for the error body.
+    fn kind(&self) -> &'static str {
+        match self {
+            MessageError::Custom(_) => "invalid_field",
+            MessageError::NoStartLine(_) => "no_start_line",
+            MessageError::MalformedStartLine(_) => "malformed_start_line",
+            MessageError::MalformedHeader(_) => "malformed_header",
+            MessageError::ParseUtf8(_) => "not_utf8",
+            MessageError::DecodeJson(_) => "invalid_json",
+            MessageError::InvalidBody(_) => "invalid_body",
+            MessageError::UnsupportedVersion(_) => "unsupported_version",
+            MessageError::MalformedBinary(_) => "malformed_binary",
+            MessageError::Cbor(_) => "invalid_cbor",
+        }
+    }
+
+    /// JSON body explaining the error.
+    pub fn body(&self) -> Value {
+        match self {
+            MessageError::UnsupportedVersion(_) => json!({
+                "error": self.kind(),
+                "detail": self.to_string(),
+                "supported": { "min": MIN_VERSION.to_string(), "max": MAX_VERSION.to_string() },
+            }),
+            _ => json!({ "error": self.kind(), "detail": self.to_string() }),
+        }
+    }
+
+    /// Error response for a message that failed to decode, `id` is 0 when
+    /// it could not be recovered.
+    pub fn to_response(&self, id: usize) -> Response {
+        Response::new(
+            PROTO_NAME.to_string(),
+            PROTO_NUMBER.to_string(),
+            id,
+            self.code(),
+            self.reason().to_string(),
+            Some(self.body())
+        )
+    }
 }
 
+#[derive(Clone)]
 pub struct Request {
     pub protocol: String,
     pub version: String,
     pub id: usize,
-    pub action: String,
-    pub kind: String,
+    pub action: Action,
+    pub kind: Kind,
+    pub headers: Headers,
     pub body: Option<Value>,
 }
 
 impl Request {
-    pub fn new(protocol: String, version: String, id: usize, action: String, kind: String, body: Option<Value>) -> Self {
+    pub fn new(protocol: String, version: String, id: usize, action: Action, kind: Kind, body: Option<Value>) -> Self {
         Request {
             protocol,
             version,
             id,
             action,
             kind,
+            headers: Headers::new(),
             body,
         }
     }
 
-    pub fn encode(&self) -> String {
-        let sl = format!("{}/{} {} {} {}\n", self.protocol, self.version, self.id, self.action, self.kind);
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
 
-        // Don't move out of self.body; borrow and format the JSON payload if present.
-        let body = match &self.body {
-            Some(v) => v.to_string(),
-            None => "".to_string(),
-        };
+    /// Decodes the body into the type the route expects. A missing body
+    /// decodes as `{}`.
+    pub fn body_as<T: DeserializeOwned>(&self) -> Result<T, MessageError> {
+        let body = self.body.clone().unwrap_or_else(|| json!({}));
+        serde_json::from_value(body).map_err(|e| MessageError::InvalidBody(e.to_string()))
+    }
+
+    /// Builds a response to this request, echoing its correlation id.
+    pub fn response(&self, code: usize, text: &str, body: Option<Value>) -> Response {
+        let mut response = Response::new(self.protocol.clone(), self.version.clone(), self.id, code, text.to_string(), body);
+        if let Some(correlation) = self.headers.get(CORRELATION_ID) {
+            response.headers.insert(CORRELATION_ID, correlation);
+        }
+        response
+    }
 
-        let s = format!("{}{}", sl, body);
+    pub fn encode(&self) -> String {
+        // Same order build_request expects: id action kind proto/version
+        let sl = format!("{} {} {} {}/{}", self.id, self.action, self.kind, self.protocol, self.version);
 
-        s
+        encode_message(&sl, &self.headers, self.body.as_ref())
+    }
+
+    pub fn encode_as(&self, encoding: Encoding) -> Result<Bytes, MessageError> {
+        match encoding {
+            Encoding::Text => Ok(Bytes::from(self.encode())),
+            Encoding::Binary => binary::encode_request(self),
+        }
     }
 }
 
@@ -79,17 +188,19 @@ impl fmt::Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let json = self.body.as_ref().unwrap_or_default();
 
-        write!(f, "proto: {}\nver: {}\nid: {}\naction: {}\nkind: {}\nbody: {}\n", 
-            self.protocol, self.version, self.id, self.action, self.kind, json)
+        write!(f, "proto: {}\nver: {}\nid: {}\naction: {}\nkind: {}\nheaders: {:?}\nbody: {}\n", 
+            self.protocol, self.version, self.id, self.action, self.kind, self.headers, json)
     }
 }
 
+#[derive(Clone)]
 pub struct Response {
     pub protocol: String,
     pub version: String,
     pub id: usize,
     pub code: usize,
     pub text: String,
+    pub headers: Headers,
     pub body: Option<Value>
 }
 
@@ -101,22 +212,27 @@ impl Response {
             id,
             code,
             text,
+            headers: Headers::new(),
             body,
         }
     }
 
-    pub fn encode(&self) -> String {
-        let sl = format!("{}/{} {} {} {}\n", self.protocol, self.version, self.id, self.code, self.text);
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
 
-        // Don't move out of self.body; borrow and format the JSON payload if present.
-        let body = match &self.body {
-            Some(v) => v.to_string(),
-            None => "".to_string(),
-        };
+    pub fn encode(&self) -> String {
+        let sl = format!("{}/{} {} {} {}", self.protocol, self.version, self.id, self.code, self.text);
 
-        let s = format!("{}{}", sl, body);
+        encode_message(&sl, &self.headers, self.body.as_ref())
+    }
 
-        s
+    pub fn encode_as(&self, encoding: Encoding) -> Result<Bytes, MessageError> {
+        match encoding {
+            Encoding::Text => Ok(Bytes::from(self.encode())),
+            Encoding::Binary => binary::encode_response(self),
+        }
     }
 }
 
@@ -124,112 +240,479 @@ impl fmt::Display for Response {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let json = self.body.as_ref().unwrap_or_default();
 
-        write!(f, "proto: {}\nver: {}\nid: {}\ncode: {}\ntext: {}\nbody: {}\n", 
-            self.protocol, self.version, self.id, self.code, self.text, json)
+        write!(f, "proto: {}\nver: {}\nid: {}\ncode: {}\ntext: {}\nheaders: {:?}\nbody: {}\n", 
+            self.protocol, self.version, self.id, self.code, self.text, self.headers, json)
+    }
+}
+
+/// Something a device reports without being asked, a reading or an alarm.
+#[derive(Clone)]
+pub struct Event {
+    pub protocol: String,
+    pub version: String,
+    /// Device the event comes from, as the device names itself.
+    pub source: String,
+    pub kind: Kind,
+    pub name: String,
+    pub headers: Headers,
+    pub body: Option<Value>,
+}
+
+impl Event {
+    pub fn new(protocol: String, version: String, source: String, kind: Kind, name: String, body: Option<Value>) -> Self {
+        Event {
+            protocol,
+            version,
+            source,
+            kind,
+            name,
+            headers: Headers::new(),
+            body,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn encode(&self) -> String {
+        // Same order build_event expects: EVENT source kind name proto/version
+        let sl = format!("{} {} {} {} {}/{}", EVENT_TOKEN, self.source, self.kind, self.name, self.protocol, self.version);
+
+        encode_message(&sl, &self.headers, self.body.as_ref())
+    }
+
+    pub fn encode_as(&self, encoding: Encoding) -> Result<Bytes, MessageError> {
+        match encoding {
+            Encoding::Text => Ok(Bytes::from(self.encode())),
+            Encoding::Binary => binary::encode_event(self),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = self.body.as_ref().unwrap_or_default();
+
+        write!(f, "proto: {}\nver: {}\nsource: {}\nkind: {}\nname: {}\nheaders: {:?}\nbody: {}\n", 
+            self.protocol, self.version, self.source, self.kind, self.name, self.headers, json)
     }
 }
 
+#[derive(Clone)]
 pub enum Message {
     Request(Request),
-    Response(Response)
+    Response(Response),
+    Event(Event),
+}
+
+impl Message {
+    pub fn encode_as(&self, encoding: Encoding) -> Result<Bytes, MessageError> {
+        match self {
+            Message::Request(req) => req.encode_as(encoding),
+            Message::Response(resp) => resp.encode_as(encoding),
+            Message::Event(event) => event.encode_as(encoding),
+        }
+    }
+}
+
+
+/// Start line, header lines if any, blank line, body.
+/// Without headers the blank line is left out, as older peers expect.
+fn encode_message(start_line: &str, headers: &Headers, body: Option<&Value>) -> String {
+    let body = body.map(|v| v.to_string()).unwrap_or_default();
+
+    if headers.is_empty() {
+        format!("{}\n{}", start_line, body)
+    } else {
+        format!("{}\n{}\n{}", start_line, headers, body)
+    }
 }
 
+/// Splits off the first line, without its `\n` or `\r\n`.
+fn next_line(buf: &[u8]) -> (&[u8], &[u8]) {
+    let (line, rest) = match buf.iter().position(|c| *c == b'\n') {
+        Some(i) => (&buf[..i], &buf[i + 1..]),
+        None => (buf, &buf[buf.len()..]),
+    };
+
+    (line.strip_suffix(b"\r").unwrap_or(line), rest)
+}
 
 pub fn decode_message(payload: Bytes) -> Result<Message, MessageError> {
-    // All messages are ASCII and have an start_line followed by '\n'.
-    // They may have or not a body after the '\n'.
-    // If present the body will be JSON.
+    // All messages have a start line followed by '\n', then optional
+    // `Key: Value` header lines ending at a blank line.
+    // They may have or not a body after that. If present it is JSON.
+    // Messages without headers may start the body right after the start line.
 
-    let mut iter = payload.split(|c| {
-        *c == '\n' as u8
-    });
+    if Encoding::of(&payload) == Encoding::Binary {
+        return binary::decode(&payload);
+    }
 
     // Get the start line or return error
-    let l = iter.next().ok_or(MessageError::NoStartLine("".to_string()))?;
+    if payload.is_empty() {
+        return Err(MessageError::NoStartLine("empty message".to_string()));
+    }
+    let (l, mut rest) = next_line(&payload);
+    let line = std::str::from_utf8(l)?;
+
+    let mut headers = Headers::new();
+    while !rest.is_empty() {
+        let (l, next) = next_line(rest);
+        if l.is_empty() {
+            // Blank line, end of headers
+            rest = next;
+            break;
+        }
 
-    //let line: &str;
-    let line = match std::str::from_utf8(l) {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(MessageError::ParseUtf8(e));
+        let header_line = std::str::from_utf8(l)?;
+        match Headers::parse_line(header_line) {
+            Some((name, value)) => {
+                headers.insert(name, value);
+                rest = next;
+            }
+            // No headers at all, the body follows the start line
+            None if headers.is_empty() => break,
+            None => return Err(MessageError::MalformedHeader(header_line.to_string())),
         }
-    };
+    }
 
     // get the body as an option
-    let json: Option<Value> = match iter.next() {
-        Some(a) => {
-            match serde_json::from_slice::<Value>(&a) {
-                Ok(j) => Some(j),
-                Err(e) => {
-                    eprintln!("JSON parse error: {} -- raw={:?}", e, payload);
-                    return Err(MessageError::DecodeJson(e));
-                    // optionally send an error response
-                }
+    let json: Option<Value> = if rest.trim_ascii().is_empty() {
+        None
+    } else {
+        match serde_json::from_slice::<Value>(rest) {
+            Ok(j) => Some(j),
+            Err(e) => {
+                eprintln!("JSON parse error: {} -- raw={:?}", e, payload);
+                return Err(MessageError::DecodeJson(e));
             }
-        },
-        None => None
+        }
     };
 
-    build_message(line, json)
+    build_message(line, headers, json)
 }
 
-fn build_message(line: &str, json: Option<Value>) -> Result<Message, MessageError> {
-    //let sline = line.to_ascii_lowercase();
+/// Best effort look at the start line of a message that failed to decode,
+/// returns its id if there is one.
+pub fn recover_id(payload: &[u8]) -> Option<usize> {
+    if Encoding::of(payload) == Encoding::Binary {
+        return binary::recover_id(payload);
+    }
+
+    let line = std::str::from_utf8(next_line(payload).0).ok()?;
     let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
 
-    if tokens.len() != START_LINE_ITEMS {
-        return Err(MessageError::MalformedStartLine(std::format!("expected {} tokens, got {}", START_LINE_ITEMS, tokens.len())));
-    } 
+    // Responses start with proto/version, requests with the id, events have none
+    if *tokens.first()? == EVENT_TOKEN {
+        return None;
+    }
+    let id = if tokens.first()?.contains('/') { tokens.get(1)? } else { tokens.first()? };
+    id.parse::<usize>().ok()
+}
 
-    if tokens[0] == PROTO_VERSION {
+/// Splits off the first whitespace separated token, `None` if there is none.
+fn split_token(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    if s.is_empty() {
+        return None;
+    }
+
+    let end = s.find(|c: char| c.is_ascii_whitespace()).unwrap_or(s.len());
+    Some((&s[..end], &s[end..]))
+}
+
+/// Splits `SMSG/0.1` into protocol and version, rejecting versions the
+/// gateway does not speak.
+fn split_proto(proto_ver: &str) -> Result<(String, String), MessageError> {
+    let unsupported = || MessageError::UnsupportedVersion(proto_ver.to_string());
+
+    let (protocol, version) = proto_ver.split_once('/').ok_or_else(unsupported)?;
+    if protocol != PROTO_NAME {
+        return Err(unsupported());
+    }
+
+    match version.parse::<Version>() {
+        Ok(v) if v.is_supported() => Ok((protocol.to_string(), version.to_string())),
+        _ => Err(unsupported()),
+    }
+}
+
+fn build_message(line: &str, headers: Headers, json: Option<Value>) -> Result<Message, MessageError> {
+    let Some((first, _)) = split_token(line) else {
+        return Err(MessageError::MalformedStartLine("empty start line".to_string()));
+    };
+
+    if first.strip_prefix(PROTO_NAME).is_some_and(|rest| rest.starts_with('/')) {
         // This is a response
-        let response = build_response(&tokens, json)?;
+        let response = build_response(line, headers, json)?;
         Ok(Message::Response(response))
+    } else if first == EVENT_TOKEN {
+        let event = build_event(line, headers, json)?;
+        Ok(Message::Event(event))
     } else {
         // This is a request
-        let request = build_request(&tokens, json)?;
+        let request = build_request(line, headers, json)?;
         Ok(Message::Request(request))
     }
 }
 
-fn build_request(tokens: &Vec<&str>, body: Option<Value>) -> Result<Request, MessageError> {
+/// `id action kind proto/version`. The kind is everything between the
+/// action and the last token, so it may contain spaces.
+fn build_request(line: &str, headers: Headers, body: Option<Value>) -> Result<Request, MessageError> {
+    let malformed = || MessageError::MalformedStartLine(format!("expected \"id action kind {}/version\", got {:?}", PROTO_NAME, line));
+
+    let (id, rest) = split_token(line).ok_or_else(malformed)?;
+    let (action, rest) = split_token(rest).ok_or_else(malformed)?;
+    let (kind, proto_ver) = rest.trim().rsplit_once(|c: char| c.is_ascii_whitespace()).ok_or_else(malformed)?;
+
     // Parse id
-    let id = match tokens[RequestTokenIndex::IdIndex as usize].parse::<usize>() {
+    let id = match id.parse::<usize>() {
         Ok(v) => v,
         Err(_) => return Err(MessageError::Custom("invalid id".to_string())),
     };
 
-    let action = tokens[RequestTokenIndex::ActionIndex as usize].to_string();
-    let kind = tokens[RequestTokenIndex::KindIndex as usize].to_string();
+    let (protocol, version) = split_proto(proto_ver)?;
+
+    Ok(Request { protocol, version, id, action: Action::from(action), kind: Kind::from(kind.trim_end()), headers, body })
+}
+
+/// `EVENT source kind name proto/version`. The name is everything between
+/// the kind and the last token, so it may contain spaces.
+fn build_event(line: &str, headers: Headers, body: Option<Value>) -> Result<Event, MessageError> {
+    let malformed = || MessageError::MalformedStartLine(format!("expected \"{} source kind name {}/version\", got {:?}", EVENT_TOKEN, PROTO_NAME, line));
+
+    let (_, rest) = split_token(line).ok_or_else(malformed)?;
+    let (source, rest) = split_token(rest).ok_or_else(malformed)?;
+    let (kind, rest) = split_token(rest).ok_or_else(malformed)?;
+    let (name, proto_ver) = rest.trim().rsplit_once(|c: char| c.is_ascii_whitespace()).ok_or_else(malformed)?;
 
-    // tokens[3] expected to be protocol/version like "SMSG/0.1"
-    let proto_ver = tokens[RequestTokenIndex::ProtoIndex as usize];
-    let mut parts = proto_ver.splitn(2, '/');
-    let protocol = parts.next().unwrap_or("").to_string();
-    let version = parts.next().unwrap_or("").to_string();
+    let (protocol, version) = split_proto(proto_ver)?;
 
-    Ok(Request { protocol, version, id, action, kind, body })
+    Ok(Event { protocol, version, source: source.to_string(), kind: Kind::from(kind), name: name.trim_end().to_string(), headers, body })
 }
 
-fn build_response(tokens: &Vec<&str>, body: Option<Value>) -> Result<Response, MessageError> {
+/// `proto/version id code reason phrase`. The reason phrase is the rest of
+/// the line and may be several words, or none.
+fn build_response(line: &str, headers: Headers, body: Option<Value>) -> Result<Response, MessageError> {
+    let malformed = || MessageError::MalformedStartLine(format!("expected \"{}/version id code reason\", got {:?}", PROTO_NAME, line));
+
+    let (proto_ver, rest) = split_token(line).ok_or_else(malformed)?;
+    let (id, rest) = split_token(rest).ok_or_else(malformed)?;
+    let (code, rest) = split_token(rest).ok_or_else(malformed)?;
+
     // Parse id
-    let id = match tokens[ResponseTokenIndex::IdIndex as usize].parse::<usize>() {
+    let id = match id.parse::<usize>() {
         Ok(v) => v,
         Err(_) => return Err(MessageError::Custom("id is non num".to_string())),
     };
 
-    let code = match tokens[ResponseTokenIndex::CodeIndex as usize].parse::<usize>() {
+    let code = match code.parse::<usize>() {
         Ok(v) => v,
         Err(_) => return Err(MessageError::Custom("code is non num".to_string())),
     };
 
-    let text = tokens[ResponseTokenIndex::TextIndex as usize].to_string();
+    let text = rest.trim().to_string();
+    let (protocol, version) = split_proto(proto_ver)?;
+
+    Ok(Response { protocol, version, id, code, text, headers, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_request(s: &str) -> Request {
+        match decode_message(Bytes::from(s.to_string())) {
+            Ok(Message::Request(req)) => req,
+            Ok(_) => panic!("decoded {:?} as something else", s),
+            Err(e) => panic!("could not decode {:?}: {}", s, e),
+        }
+    }
+
+    fn decode_response(s: &str) -> Response {
+        match decode_message(Bytes::from(s.to_string())) {
+            Ok(Message::Response(resp)) => resp,
+            Ok(_) => panic!("decoded {:?} as something else", s),
+            Err(e) => panic!("could not decode {:?}: {}", s, e),
+        }
+    }
+
+    #[test]
+    fn multi_word_reason_phrase() {
+        let resp = decode_response("SMSG/0.1 7 404 Not Found\n");
+
+        assert_eq!(resp.id, 7);
+        assert_eq!(resp.code, 404);
+        assert_eq!(resp.text, "Not Found");
+    }
+
+    #[test]
+    fn reason_phrase_tolerates_extra_whitespace() {
+        let resp = decode_response("SMSG/0.1  7\t503   Service Unavailable  \r\n");
 
-    // tokens[0] expected to be protocol/version like "SMSG/0.1"
-    let proto_ver = tokens[ResponseTokenIndex::ProtoIndex as usize];
-    let mut parts = proto_ver.splitn(2, '/');
-    let protocol = parts.next().unwrap_or("").to_string();
-    let version = parts.next().unwrap_or("").to_string();
+        assert_eq!(resp.code, 503);
+        assert_eq!(resp.text, "Service Unavailable");
+    }
+
+    #[test]
+    fn empty_reason_phrase() {
+        let resp = decode_response("SMSG/0.1 7 200\n");
+
+        assert_eq!(resp.code, 200);
+        assert_eq!(resp.text, "");
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let resp = Response::new(
+            PROTO_NAME.to_string(),
+            PROTO_NUMBER.to_string(),
+            42,
+            405,
+            "Method Not Allowed".to_string(),
+            Some(json!({ "allow": ["GET"] }))
+        ).with_header("Correlation-Id", "abc");
+
+        let decoded = decode_response(&resp.encode());
+
+        assert_eq!(decoded.protocol, PROTO_NAME);
+        assert_eq!(decoded.version, PROTO_NUMBER);
+        assert_eq!(decoded.id, 42);
+        assert_eq!(decoded.code, 405);
+        assert_eq!(decoded.text, "Method Not Allowed");
+        assert_eq!(decoded.headers, resp.headers);
+        assert_eq!(decoded.body, resp.body);
+    }
+
+    #[test]
+    fn request_tolerates_extra_whitespace() {
+        let req = decode_request("  12   GET\ttemperature   SMSG/0.1 \r\n");
+
+        assert_eq!(req.id, 12);
+        assert_eq!(req.action, Action::Get);
+        assert_eq!(req.kind, Kind::Other("temperature".to_string()));
+        assert_eq!(req.version, PROTO_NUMBER);
+    }
+
+    #[test]
+    fn multi_word_request_kind() {
+        let req = decode_request("12 GET living room SMSG/0.1\n");
+
+        assert_eq!(req.action, Action::Get);
+        assert_eq!(req.kind.as_str(), "living room");
+        assert_eq!(req.protocol, PROTO_NAME);
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let req = Request::new(
+            PROTO_NAME.to_string(),
+            PROTO_NUMBER.to_string(),
+            3,
+            Action::Set,
+            Kind::from("living room"),
+            Some(json!({ "on": true }))
+        ).with_header("Timeout", "500");
+
+        let decoded = decode_request(&req.encode());
+
+        assert_eq!(decoded.id, 3);
+        assert_eq!(decoded.action, Action::Set);
+        assert_eq!(decoded.kind.as_str(), "living room");
+        assert_eq!(decoded.headers, req.headers);
+        assert_eq!(decoded.body, req.body);
+    }
+
+    #[test]
+    fn unknown_actions_and_kinds_pass_through() {
+        let req = decode_request("5 RESET thermostat SMSG/0.1\n");
+
+        assert_eq!(req.action, Action::Other("RESET".to_string()));
+        assert_eq!(req.kind, Kind::Other("thermostat".to_string()));
+        assert_eq!(decode_request(&req.encode()).action.as_str(), "RESET");
+    }
+
+    #[test]
+    fn unsupported_versions_are_rejected() {
+        for msg in ["1 GET metrics SMSG/9.0\n", "SMSG/9.0 1 200 OK\n", "1 GET metrics HTTP/1.1\n", "1 GET metrics SMSG/x\n"] {
+            match decode_message(Bytes::from(msg)) {
+                Err(e @ MessageError::UnsupportedVersion(_)) => assert_eq!(e.code(), 505),
+                _ => panic!("{:?} should be rejected", msg),
+            }
+        }
+    }
 
-    Ok(Response { protocol, version, id, code, text, body })
-}   
+    #[test]
+    fn event_round_trip() {
+        let event = Event::new(
+            PROTO_NAME.to_string(),
+            PROTO_NUMBER.to_string(),
+            "door-3".to_string(),
+            Kind::from("lock"),
+            "forced open".to_string(),
+            Some(json!({ "at": 1700000000 }))
+        );
+
+        let Ok(Message::Event(decoded)) = decode_message(Bytes::from(event.encode())) else {
+            panic!("event did not decode");
+        };
+
+        assert_eq!(decoded.source, "door-3");
+        assert_eq!(decoded.kind.as_str(), "lock");
+        assert_eq!(decoded.name, "forced open");
+        assert_eq!(decoded.body, event.body);
+    }
+
+    #[test]
+    fn short_start_lines_are_malformed() {
+        for line in ["12 GET SMSG/0.1\n", "12 GET\n", "SMSG/0.1 7\n", "EVENT door-3 lock SMSG/0.1\n", "\n"] {
+            assert!(
+                matches!(decode_message(Bytes::from(line)), Err(MessageError::MalformedStartLine(_))),
+                "{:?} should be malformed",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn bad_body_echoes_the_request_id() {
+        let payload = b"12 SET metrics SMSG/0.1\n{not json";
+        let err = match decode_message(Bytes::from_static(payload)) {
+            Err(e) => e,
+            Ok(_) => panic!("decoded a broken body"),
+        };
+        assert_eq!(recover_id(payload), Some(12));
+
+        let resp = err.to_response(recover_id(payload).unwrap_or(0));
+        assert_eq!((resp.id, resp.code), (12, 400));
+        assert_eq!(resp.body.unwrap()["error"], "invalid_json");
+    }
+
+    #[test]
+    fn response_id_is_the_second_token() {
+        assert_eq!(recover_id(b"SMSG/0.1 7 200 OK\n{not json"), Some(7));
+    }
+
+    #[test]
+    fn no_id_to_recover() {
+        for payload in [&b"EVENT door-3 lock opened SMSG/0.1\n"[..], b"garbage here\n", b"", b"\xff\xfe"] {
+            assert_eq!(recover_id(payload), None, "{:?}", payload);
+        }
+
+        let resp = MessageError::NoStartLine(String::new()).to_response(recover_id(b"garbage").unwrap_or(0));
+        assert_eq!((resp.id, resp.code), (0, 400));
+    }
+
+    #[test]
+    fn unsupported_version_lists_the_supported_range() {
+        let resp = MessageError::UnsupportedVersion("SMSG/9.0".to_string()).to_response(3);
+        assert_eq!((resp.id, resp.code, resp.text.as_str()), (3, 505, "Version Not Supported"));
+
+        let body = resp.body.unwrap();
+        assert_eq!(body["error"], "unsupported_version");
+        assert_eq!(body["supported"]["min"], MIN_VERSION.to_string());
+        assert_eq!(body["supported"]["max"], MAX_VERSION.to_string());
+    }
+}
@@ -0,0 +1,105 @@
+//! Actions and kinds of the start line of a request.
+//!
+//! Values the gateway knows about get their own variant, anything else is
+//! kept as `Other` so it can still be forwarded to devices.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Action {
+    Get,
+    Set,
+    /// Version handshake, answered by the connection itself.
+    Hello,
+    /// Start receiving unsolicited messages, answered by the connection itself.
+    Subscribe,
+    Unsubscribe,
+    Other(String),
+}
+
+impl Action {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Action::Get => "GET",
+            Action::Set => "SET",
+            Action::Hello => "HELLO",
+            Action::Subscribe => "SUBSCRIBE",
+            Action::Unsubscribe => "UNSUBSCRIBE",
+            Action::Other(s) => s,
+        }
+    }
+}
+
+impl From<&str> for Action {
+    fn from(s: &str) -> Self {
+        match s.to_ascii_uppercase().as_str() {
+            "GET" => Action::Get,
+            "SET" => Action::Set,
+            "HELLO" => Action::Hello,
+            "SUBSCRIBE" => Action::Subscribe,
+            "UNSUBSCRIBE" => Action::Unsubscribe,
+            _ => Action::Other(s.to_string()),
+        }
+    }
+}
+
+impl From<String> for Action {
+    fn from(s: String) -> Self {
+        Action::from(s.as_str())
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Kind {
+    /// Counters of the gateway's internal bus.
+    Metrics,
+    /// The gateway itself.
+    Gateway,
+    /// Events pushed by devices.
+    Events,
+    /// What a client subscribes to.
+    Topics,
+    Other(String),
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Kind::Metrics => "metrics",
+            Kind::Gateway => "gateway",
+            Kind::Events => "events",
+            Kind::Topics => "topics",
+            Kind::Other(s) => s,
+        }
+    }
+}
+
+impl From<&str> for Kind {
+    fn from(s: &str) -> Self {
+        match s.to_ascii_lowercase().as_str() {
+            "metrics" => Kind::Metrics,
+            "gateway" => Kind::Gateway,
+            "events" => Kind::Events,
+            "topics" => Kind::Topics,
+            _ => Kind::Other(s.to_string()),
+        }
+    }
+}
+
+impl From<String> for Kind {
+    fn from(s: String) -> Self {
+        Kind::from(s.as_str())
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
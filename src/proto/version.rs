@@ -0,0 +1,54 @@
+//! SMSG protocol versions and the range of them the gateway speaks.
+//!
+//! A client may open with a `HELLO` request listing the versions it speaks,
+//! sent with the lowest of them. The gateway picks the highest one both
+//! sides support and holds the connection to it.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Oldest version the gateway accepts.
+pub const MIN_VERSION: Version = Version::new(0, 1);
+/// Newest version the gateway accepts, the one it speaks by default.
+pub const MAX_VERSION: Version = Version::new(0, 1);
+
+/// `major.minor`, as found after the `/` of `SMSG/0.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl Version {
+    pub const fn new(major: u16, minor: u16) -> Self {
+        Self { major, minor }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        (MIN_VERSION..=MAX_VERSION).contains(self)
+    }
+}
+
+impl FromStr for Version {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s.split_once('.').ok_or(())?;
+
+        Ok(Version {
+            major: major.parse().map_err(|_| ())?,
+            minor: minor.parse().map_err(|_| ())?,
+        })
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Highest version of `offered` the gateway supports.
+pub fn negotiate(offered: &[Version]) -> Option<Version> {
+    offered.iter().filter(|v| v.is_supported()).max().copied()
+}
@@ -0,0 +1,247 @@
+use std::env;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::bus::Backpressure;
+use crate::server::mcodec::{Checksum, MAX_EXTENDED_FRAME_SIZE};
+
+/// How long a request forwarded to BLE waits for the device's response.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages queued for a consumer of the internal bus before its
+/// backpressure policy kicks in.
+pub const DEFAULT_QUEUE_SIZE: usize = 16;
+
+/// Where the socket is created unless configured otherwise.
+pub const DEFAULT_SOCKET_PATH: &str = "/tmp/gateway.sock";
+
+/// Owner and group may read and write, nobody else.
+pub const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// The Unix socket clients connect to.
+#[derive(Debug, Clone)]
+pub struct SocketConfig {
+    pub path: PathBuf,
+    /// Permission bits of the socket file.
+    pub mode: u32,
+    /// User owning the socket, a name or a uid. Left alone if `None`.
+    pub owner: Option<String>,
+    /// Group owning the socket, a name or a gid. Left alone if `None`.
+    pub group: Option<String>,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from(DEFAULT_SOCKET_PATH),
+            mode: DEFAULT_SOCKET_MODE,
+            owner: None,
+            group: None,
+        }
+    }
+}
+
+impl SocketConfig {
+    /// Defaults overridden by `GATEWAY_SOCKET`, `GATEWAY_SOCKET_MODE` (octal),
+    /// `GATEWAY_SOCKET_OWNER` and `GATEWAY_SOCKET_GROUP`.
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self::default();
+
+        if let Ok(path) = env::var("GATEWAY_SOCKET") {
+            config.path = PathBuf::from(path);
+        }
+        if let Ok(mode) = env::var("GATEWAY_SOCKET_MODE") {
+            config.mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+                .map_err(|e| format!("GATEWAY_SOCKET_MODE {:?}: {}", mode, e))?;
+        }
+        config.owner = env::var("GATEWAY_SOCKET_OWNER").ok();
+        config.group = env::var("GATEWAY_SOCKET_GROUP").ok();
+
+        Ok(config)
+    }
+
+    /// Held while the server runs, so a second instance does not take over
+    /// the socket.
+    pub fn lock_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+}
+
+/// Certificate the TCP listener presents, and whom it accepts.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key of the leaf.
+    pub key: PathBuf,
+    /// PEM bundle client certificates are verified against. Clients are
+    /// not asked for one without it.
+    pub client_ca: Option<PathBuf>,
+    /// Clients without a certificate get in too, as nobody in particular.
+    pub client_cert_optional: bool,
+}
+
+/// A TCP listener next to the Unix socket.
+#[derive(Debug, Clone)]
+pub struct TcpConfig {
+    pub addr: SocketAddr,
+    /// Plain TCP if `None`.
+    pub tls: Option<TlsConfig>,
+}
+
+impl TcpConfig {
+    /// Set by `GATEWAY_TCP`, TLS by `GATEWAY_TLS_CERT` and `GATEWAY_TLS_KEY`,
+    /// client certificates by `GATEWAY_TLS_CLIENT_CA` and
+    /// `GATEWAY_TLS_CLIENT_OPTIONAL`. `None` without a TCP address.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let Ok(addr) = env::var("GATEWAY_TCP") else {
+            return Ok(None);
+        };
+        let addr = addr.parse().map_err(|e| format!("GATEWAY_TCP {:?}: {}", addr, e))?;
+
+        let tls = match (env::var_os("GATEWAY_TLS_CERT"), env::var_os("GATEWAY_TLS_KEY")) {
+            (Some(cert), Some(key)) => Some(TlsConfig {
+                cert: PathBuf::from(cert),
+                key: PathBuf::from(key),
+                client_ca: env::var_os("GATEWAY_TLS_CLIENT_CA").map(PathBuf::from),
+                client_cert_optional: env::var_os("GATEWAY_TLS_CLIENT_OPTIONAL").is_some(),
+            }),
+            (None, None) => None,
+            _ => return Err("GATEWAY_TLS_CERT and GATEWAY_TLS_KEY go together".to_string()),
+        };
+
+        Ok(Some(Self { addr, tls }))
+    }
+}
+
+/// Server settings.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub socket: SocketConfig,
+    /// Also listen on TCP if set.
+    pub tcp: Option<TcpConfig>,
+    /// Also take WebSocket connections on this address if set.
+    pub websocket: Option<SocketAddr>,
+    /// Web pages whose WebSocket connections are taken, by their `Origin`.
+    /// Clients that send none are not browsers and are not checked.
+    pub websocket_origins: Vec<String>,
+    /// Payload limit of extended-length frames from and to clients.
+    pub max_extended_frame: usize,
+    /// Trailer of frames to socket clients until they send one, after that
+    /// they get the one of their last frame.
+    pub frame_checksum: Checksum,
+    /// Deadline for device responses, the client gets a 504 after it.
+    pub request_timeout: Duration,
+    /// Queue size of each socket client for BLE traffic.
+    pub client_queue: usize,
+    /// What happens when a socket client does not keep up with BLE traffic.
+    /// `Block` holds up the dispatcher, see `dispatch_backpressure`.
+    pub client_backpressure: Backpressure,
+    /// Queue size of the dispatcher for BLE traffic, before it goes to
+    /// the clients.
+    pub dispatch_queue: usize,
+    /// What happens when the dispatcher does not keep up with BLE, which
+    /// is when a `Block` client holds it up. Only `Block` here as well
+    /// makes that client slow down the centrals instead of losing messages.
+    pub dispatch_backpressure: Backpressure,
+    /// Queue size of the BLE adapter for server traffic.
+    pub ble_queue: usize,
+    /// What happens when the BLE adapter does not keep up with the server.
+    pub ble_backpressure: Backpressure,
+    /// Policy file deciding what each peer may do. Without one local peers
+    /// may do everything and remote ones nothing.
+    pub policy: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            socket: SocketConfig::default(),
+            tcp: None,
+            websocket: None,
+            websocket_origins: Vec::new(),
+            max_extended_frame: MAX_EXTENDED_FRAME_SIZE,
+            frame_checksum: Checksum::None,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            client_queue: DEFAULT_QUEUE_SIZE,
+            client_backpressure: Backpressure::DropOldest,
+            dispatch_queue: DEFAULT_QUEUE_SIZE,
+            dispatch_backpressure: Backpressure::DropOldest,
+            ble_queue: DEFAULT_QUEUE_SIZE,
+            ble_backpressure: Backpressure::DropOldest,
+            policy: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Defaults overridden by the environment: the socket and TCP settings,
+    /// `GATEWAY_WS`, `GATEWAY_WS_ORIGINS` (comma separated),
+    /// `GATEWAY_POLICY`, `GATEWAY_MAX_EXTENDED_FRAME` (bytes),
+    /// `GATEWAY_FRAME_CHECKSUM` (`none`, `crc16` or `crc32`),
+    /// `GATEWAY_REQUEST_TIMEOUT` (milliseconds), and queue sizes and
+    /// backpressure policies in `GATEWAY_CLIENT_QUEUE`,
+    /// `GATEWAY_CLIENT_BACKPRESSURE`, `GATEWAY_DISPATCH_QUEUE`,
+    /// `GATEWAY_DISPATCH_BACKPRESSURE`, `GATEWAY_BLE_QUEUE` and
+    /// `GATEWAY_BLE_BACKPRESSURE` (`block`, `drop-oldest` or `disconnect`).
+    pub fn from_env() -> Result<Self, String> {
+        let mut config = Self {
+            socket: SocketConfig::from_env()?,
+            tcp: TcpConfig::from_env()?,
+            policy: env::var_os("GATEWAY_POLICY").map(PathBuf::from),
+            websocket_origins: env::var("GATEWAY_WS_ORIGINS")
+                .map(|origins| origins.split(',').map(str::trim).filter(|o| !o.is_empty()).map(str::to_string).collect())
+                .unwrap_or_default(),
+            ..Self::default()
+        };
+
+        if let Some(addr) = parsed_env("GATEWAY_WS")? {
+            config.websocket = Some(addr);
+        }
+        if let Some(max) = parsed_env("GATEWAY_MAX_EXTENDED_FRAME")? {
+            config.max_extended_frame = max;
+        }
+        if let Some(checksum) = parsed_env("GATEWAY_FRAME_CHECKSUM")? {
+            config.frame_checksum = checksum;
+        }
+        if let Some(ms) = parsed_env("GATEWAY_REQUEST_TIMEOUT")? {
+            config.request_timeout = Duration::from_millis(ms);
+        }
+        if let Some(size) = parsed_env("GATEWAY_CLIENT_QUEUE")? {
+            config.client_queue = size;
+        }
+        if let Some(policy) = parsed_env("GATEWAY_CLIENT_BACKPRESSURE")? {
+            config.client_backpressure = policy;
+        }
+        if let Some(size) = parsed_env("GATEWAY_DISPATCH_QUEUE")? {
+            config.dispatch_queue = size;
+        }
+        if let Some(policy) = parsed_env("GATEWAY_DISPATCH_BACKPRESSURE")? {
+            config.dispatch_backpressure = policy;
+        }
+        if let Some(size) = parsed_env("GATEWAY_BLE_QUEUE")? {
+            config.ble_queue = size;
+        }
+        if let Some(policy) = parsed_env("GATEWAY_BLE_BACKPRESSURE")? {
+            config.ble_backpressure = policy;
+        }
+
+        Ok(config)
+    }
+}
+
+/// `None` if `name` is not set, an error if it does not parse.
+fn parsed_env<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| format!("{} {:?}: {}", name, value, e)),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,583 @@
+//! One client connection, on whichever listener: a reader task taking
+//! requests from the client and a writer task delivering BLE traffic to it.
+//!
+//! Whichever task ends first takes the other one down with it, then the
+//! requests still waiting on BLE are failed and a single close event with
+//! the reason is reported.
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, Mutex};
+use tokio::task;
+use tokio::time::interval;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use bytes::Bytes;
+use serde_json::json;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bus::{fanout::{RecvError, WeakFanout}, Fanout, Subscriber, ToBle, ToClient};
+use crate::proto::binary::{self, Encoding};
+use crate::proto::bodies::{EventTopics, Hello, HelloAccepted, Topics};
+use crate::proto::headers::CORRELATION_ID;
+use crate::proto::msg::{decode_message, recover_id, Message, MessageError, Request, Response, PROTO_NAME, PROTO_NUMBER};
+use crate::proto::types::{Action, Kind};
+use crate::proto::version::{negotiate, Version, MAX_VERSION, MIN_VERSION};
+use crate::server::config::ServerConfig;
+use crate::server::mcodec::{Checksum, Frame, FrameError, FrameReader, TwoByteLenSkipReserved, MAX_FRAME_SIZE};
+use crate::server::metrics::Metrics;
+use crate::server::origin::{Origin, Origins};
+use crate::server::pending::{self, Pending, PendingTable};
+use crate::server::policy::{Identity, Permissions, Policy, PUBLISH, RESPOND};
+use crate::server::router::{Handled, Router};
+use crate::server::subscriptions::{event_topics, Subscriptions};
+
+/// How often a connection looks for requests past their deadline.
+const PENDING_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Writes frames to the client.
+pub type FrameSink = Pin<Box<dyn Sink<Frame, Error = FrameError> + Send>>;
+/// Reads frames from the client.
+pub type FrameSource = Pin<Box<dyn Stream<Item = Result<Frame, FrameError>> + Send>>;
+
+/// Why a connection was closed.
+#[derive(Debug)]
+pub enum CloseReason {
+    /// The client closed its end.
+    ClientClosed,
+    /// Reading from the client failed.
+    ReadError(FrameError),
+    /// Writing to the client failed.
+    WriteError(FrameError),
+    /// The BLE side went away.
+    BusClosed,
+    /// The client did not keep up with BLE traffic.
+    TooSlow,
+    /// One of the connection tasks panicked.
+    TaskFailed(String),
+}
+
+impl CloseReason {
+    /// Whether the client can still be written to.
+    fn client_writable(&self) -> bool {
+        matches!(self, CloseReason::BusClosed | CloseReason::TooSlow | CloseReason::TaskFailed(_))
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CloseReason::ClientClosed => write!(f, "client closed"),
+            CloseReason::ReadError(e) => write!(f, "read error: {}", e),
+            CloseReason::WriteError(e) => write!(f, "write error: {}", e),
+            CloseReason::BusClosed => write!(f, "ble bus closed"),
+            CloseReason::TooSlow => write!(f, "too slow for ble traffic"),
+            CloseReason::TaskFailed(e) => write!(f, "task failed: {}", e),
+        }
+    }
+}
+
+/// State shared by the reader and writer task of a connection.
+struct Connection {
+    /// Write half, both tasks write to the client.
+    sink: Mutex<FrameSink>,
+    /// How the client wants its messages written.
+    encoding: std::sync::Mutex<Encoding>,
+    /// Trailer of the client's last frame, its replies get the same.
+    checksum: std::sync::Mutex<Option<Checksum>>,
+    /// Requests forwarded to BLE and waiting for the device to answer.
+    pending: std::sync::Mutex<PendingTable>,
+    /// Unsolicited messages the client wants.
+    subscriptions: std::sync::Mutex<Subscriptions>,
+    /// What the peer may send, from the policy.
+    permissions: Permissions,
+    shared: Arc<Shared>,
+}
+
+/// What every connection of a server shares.
+pub struct Shared {
+    /// Only the dispatcher keeps the bus open.
+    pub clients: WeakFanout<ToClient>,
+    pub transmitter: Fanout<ToBle>,
+    pub router: Router,
+    pub origins: Arc<Origins>,
+    pub metrics: Arc<Metrics>,
+    /// `None` lets local peers do everything and remote ones nothing.
+    pub policy: Option<Policy>,
+    pub config: ServerConfig,
+}
+
+impl Connection {
+    fn encoding(&self) -> Encoding {
+        *self.encoding.lock().unwrap()
+    }
+
+    /// Sends `payload` as is, flagged binary if it is a binary message.
+    async fn send_frame(&self, payload: Bytes) -> Result<(), FrameError> {
+        let mut frame = Frame::new(payload);
+        frame.header.binary = Encoding::of(&frame.payload) == Encoding::Binary;
+        frame.checksum = *self.checksum.lock().unwrap();
+
+        self.sink.lock().await.send(frame).await
+    }
+
+    /// Sends `message` in the client's encoding, falling back to text if it
+    /// does not fit the binary one.
+    async fn send_message(&self, message: &Message) -> Result<(), FrameError> {
+        let payload = match message.encode_as(self.encoding()) {
+            Ok(payload) => payload,
+            Err(e) => {
+                eprintln!("could not encode message as {}, sending text: {}", self.encoding(), e);
+                message.encode_as(Encoding::Text).unwrap_or_default()
+            }
+        };
+
+        self.send_frame(payload).await
+    }
+
+    /// Whether a message from BLE is for this client, checked before it
+    /// takes room in the client's queue.
+    fn wants(&self, m: &ToClient) -> bool {
+        match m.message.as_deref() {
+            Some(Message::Response(resp)) => self.pending.lock().unwrap().contains(resp.id),
+            message => self.subscriptions.lock().unwrap().matches(message),
+        }
+    }
+
+    async fn send_response(&self, response: Response) -> Result<(), FrameError> {
+        println!("will respond: {}", response.encode());
+
+        self.send_message(&Message::Response(response)).await
+    }
+}
+
+/// Serves a client on any byte stream, in frames of the socket codec.
+pub async fn handle_connection<S>(stream: S, identity: Identity, shared: Arc<Shared>)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (resync_tx, mut resync_rx) = mpsc::unbounded_channel();
+    let max_extended = shared.config.max_extended_frame;
+    let decoder = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE)
+        .with_max_extended_frame(max_extended)
+        .with_resync_events(resync_tx);
+    let encoder = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE)
+        .with_max_extended_frame(max_extended)
+        .with_checksum(shared.config.frame_checksum);
+    let _tracked = shared.metrics.track_codec(identity.to_string(), decoder.stats());
+
+    // split into sink (writer) and stream (reader)
+    let (reader, writer) = tokio::io::split(stream);
+    let source = FrameReader::new(FramedRead::new(reader, decoder));
+    let sink = FramedWrite::new(writer, encoder);
+
+    // Ends by itself once the codec is dropped
+    let _resync_task = task::spawn(async move {
+        while let Some(ev) = resync_rx.recv().await {
+            match ev.cause {
+                FrameError::Corrupt { .. } => eprintln!("dropped {}", ev.cause),
+                _ => eprintln!("frame resync, dropped {} bytes: {}", ev.dropped, ev.cause),
+            }
+        }
+    });
+
+    serve(Box::pin(sink), Box::pin(source), identity, shared).await;
+}
+
+/// Runs a connection on frames from and to the client, however they are
+/// carried.
+pub async fn serve(sink: FrameSink, source: FrameSource, identity: Identity, shared: Arc<Shared>) {
+    let permissions = match &shared.policy {
+        Some(policy) => policy.permissions(&identity),
+        // Only a policy lets anybody in over the network
+        None if identity.remote.is_some() => Permissions::default(),
+        None => Permissions::unrestricted(),
+    };
+    println!("peer {} roles={:?}", identity, permissions.roles);
+
+    let conn = Arc::new(Connection {
+        sink: Mutex::new(sink),
+        encoding: std::sync::Mutex::new(Encoding::default()),
+        checksum: std::sync::Mutex::new(None),
+        pending: std::sync::Mutex::new(PendingTable::default()),
+        subscriptions: std::sync::Mutex::new(Subscriptions::default()),
+        permissions,
+        shared,
+    });
+
+    // Once the bus is gone, connections get a subscription that is already closed
+    let wants = conn.clone();
+    let subs = conn.shared.clients.upgrade().unwrap_or_default().subscribe_filtered(
+        conn.shared.config.client_queue,
+        conn.shared.config.client_backpressure,
+        Box::new(move |m| wants.wants(m)),
+    );
+
+    let mut reader_task = task::spawn(read_client(source, conn.clone()));
+    let mut writer_task = task::spawn(write_client(subs, conn.clone()));
+
+    // The first one to end takes the other one down, which also drops
+    // the broadcast subscription held by the writer.
+    let res = tokio::select! {
+        res = &mut reader_task => {
+            writer_task.abort();
+            let _ = writer_task.await;
+            res
+        }
+        res = &mut writer_task => {
+            reader_task.abort();
+            let _ = reader_task.await;
+            res
+        }
+    };
+
+    let reason = res.unwrap_or_else(|e| CloseReason::TaskFailed(e.to_string()));
+
+    // Nobody is left to deliver the responses to these
+    let failed = conn.pending.lock().unwrap().drain();
+    if reason.client_writable() {
+        for req in failed {
+            let response = error_response(req, 503, "Service Unavailable", format!("connection closing: {}", reason));
+            if conn.send_response(response).await.is_err() {
+                break;
+            }
+        }
+    } else if !failed.is_empty() {
+        eprintln!("dropped {} pending requests", failed.len());
+    }
+
+    println!("connection closed: {}", reason);
+}
+
+/// Takes messages from the client and forwards them to BLE.
+async fn read_client(mut source: FrameSource, conn: Arc<Connection>) -> CloseReason {
+    // Set once the client said HELLO, any supported version goes until then
+    let mut version: Option<Version> = None;
+    // Set by HELLO too, until then replies follow the flag of the last frame
+    let mut encoding: Option<Encoding> = None;
+
+    while let Some(frame_res) = source.next().await {
+        let frame = match frame_res {
+            Ok(frame) => frame,
+            Err(e) => return CloseReason::ReadError(e),
+        };
+
+        if frame.checksum.is_some() {
+            *conn.checksum.lock().unwrap() = frame.checksum;
+        }
+
+        let bytes_payload = frame.payload;
+        if encoding.is_none() {
+            *conn.encoding.lock().unwrap() = if frame.header.binary { Encoding::Binary } else { Encoding::Text };
+        }
+
+        let decoded = if frame.header.binary {
+            binary::decode(&bytes_payload)
+        } else {
+            decode_message(bytes_payload.clone())
+        };
+
+        match decoded {
+            Ok(Message::Request(mut req)) => {
+                println!("req=\n{}", req);
+
+                // Requests must stick to the version the client negotiated
+                if let Some(v) = version.filter(|v| req.version.parse::<Version>().ok() != Some(*v)) {
+                    let e = MessageError::UnsupportedVersion(format!("{}/{}", req.protocol, req.version));
+                    let mut body = e.body();
+                    body["negotiated"] = json!(v.to_string());
+
+                    if let Err(e) = conn.send_response(req.response(e.code(), e.reason(), Some(body))).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                }
+
+                if !conn.permissions.allows(&req.action, Some(&req.kind)) {
+                    let body = forbidden(&format!("{} {}", req.action, req.kind), &conn.permissions);
+                    if let Err(e) = conn.send_response(req.response(403, "Forbidden", Some(body))).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                }
+
+                if req.action == Action::Hello {
+                    // Answered in the encoding the client said HELLO in
+                    let response = hello(&req, conn.encoding(), &mut version, &mut encoding);
+                    if let Err(e) = conn.send_response(response).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    if let Some(encoding) = encoding {
+                        *conn.encoding.lock().unwrap() = encoding;
+                    }
+                    continue;
+                }
+
+                if matches!(req.action, Action::Subscribe | Action::Unsubscribe) && matches!(req.kind, Kind::Topics | Kind::Events) {
+                    if let Err(e) = conn.send_response(subscribe(&req, &conn)).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                }
+
+                let res = match conn.shared.router.route(&req) {
+                    Handled::Local(response) => conn.send_response(response).await,
+                    Handled::Forward => forward_request(req, &conn).await,
+                    Handled::Both(response) => {
+                        // Nothing waits for the device, its response reaches nobody
+                        req.id = pending::reserve_id();
+                        if conn.shared.transmitter.publish(ToBle::broadcast(Message::Request(req))).await == 0 {
+                            println!("transmitter err: no ble receiver");
+                        }
+                        conn.send_response(response).await
+                    }
+                };
+
+                if let Err(e) = res {
+                    return CloseReason::WriteError(e);
+                }
+            }
+
+            Ok(Message::Response(resp)) => {
+                println!("resp=\n{}", resp);
+
+                if !conn.permissions.allows(&Action::from(RESPOND), None) {
+                    let body = forbidden(RESPOND, &conn.permissions);
+                    let response = Response::new(resp.protocol, resp.version, resp.id, 403, "Forbidden".to_string(), Some(body));
+                    if let Err(e) = conn.send_response(response).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                }
+
+                // Only the central that asked gets the answer, nobody if it is unknown
+                let Some(origin) = conn.shared.origins.take(resp.id) else {
+                    let error = format!("no BLE request {} waits for a response, it is unknown or older than {:?}", resp.id, conn.shared.origins.ttl());
+                    let response = Response::new(resp.protocol, resp.version, resp.id, 404, "Not Found".to_string(), Some(json!({ "error": error })));
+                    if let Err(e) = conn.send_response(response).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                };
+
+                if conn.shared.transmitter.publish(route_response(resp, origin)).await == 0 {
+                    println!("transmitter err: no ble receiver");
+                }
+            }
+
+            Ok(Message::Event(event)) => {
+                println!("event=\n{}", event);
+
+                if !conn.permissions.allows(&Action::from(PUBLISH), Some(&event.kind)) {
+                    let body = forbidden(&format!("{} {}", PUBLISH, event.kind), &conn.permissions);
+                    let response = Response::new(event.protocol, event.version, 0, 403, "Forbidden".to_string(), Some(body));
+                    if let Err(e) = conn.send_response(response).await {
+                        return CloseReason::WriteError(e);
+                    }
+                    continue;
+                }
+
+                if conn.shared.transmitter.publish(ToBle::broadcast(Message::Event(event))).await == 0 {
+                    println!("transmitter err: no ble receiver");
+                }
+            }
+
+            Err(e) => {
+                eprintln!("could not decode message {}", e);
+
+                // Tell the client, with its id if we can still find it
+                let id = recover_id(&bytes_payload).unwrap_or(0);
+                if let Err(e) = conn.send_response(e.to_response(id)).await {
+                    return CloseReason::WriteError(e);
+                }
+            }
+        }
+    }
+
+    CloseReason::ClientClosed
+}
+
+/// Answers a `HELLO`, settling the version and encoding the connection
+/// speaks. `current` is the encoding in use until then.
+fn hello(req: &Request, current: Encoding, version: &mut Option<Version>, encoding: &mut Option<Encoding>) -> Response {
+    let offer = match req.body_as::<Hello>() {
+        Ok(offer) => offer,
+        Err(e) => return req.response(e.code(), e.reason(), Some(e.body())),
+    };
+
+    let offered: Vec<Version> = offer.versions.iter().filter_map(|v| v.parse().ok()).collect();
+    let Some(agreed) = negotiate(&offered) else {
+        let e = MessageError::UnsupportedVersion(offer.versions.join(", "));
+        return req.response(e.code(), e.reason(), Some(e.body()));
+    };
+
+    *version = Some(agreed);
+
+    // First one the gateway knows, text is always understood
+    if !offer.encodings.is_empty() {
+        *encoding = Some(offer.encodings.iter().find_map(|e| Encoding::from_name(e)).unwrap_or(Encoding::Text));
+    }
+    let writes = encoding.unwrap_or(current);
+
+    println!("client speaks {}/{} in {}", PROTO_NAME, agreed, writes);
+
+    let accepted = HelloAccepted {
+        version: agreed.to_string(),
+        min: MIN_VERSION.to_string(),
+        max: MAX_VERSION.to_string(),
+        encoding: writes.to_string(),
+    };
+
+    let mut response = req.response(200, "OK", Some(json!(accepted)));
+    response.version = agreed.to_string();
+    response
+}
+
+/// Answers `SUBSCRIBE` and `UNSUBSCRIBE` with the topics the client is
+/// subscribed to afterwards.
+fn subscribe(req: &Request, conn: &Connection) -> Response {
+    let topics = match req.kind {
+        Kind::Events => req.body_as::<EventTopics>().map(event_topics),
+        _ => req.body_as::<Topics>().map(|t| t.topics),
+    };
+
+    let topics = match topics {
+        Ok(topics) => topics,
+        Err(e) => return req.response(e.code(), e.reason(), Some(e.body())),
+    };
+
+    let mut subscriptions = conn.subscriptions.lock().unwrap();
+    if req.action == Action::Subscribe {
+        subscriptions.subscribe(topics);
+    } else {
+        subscriptions.unsubscribe(topics);
+    }
+
+    req.response(200, "OK", Some(json!(subscriptions.topics())))
+}
+
+/// Sends `req` to BLE and waits for the device's response to it.
+async fn forward_request(mut req: Request, conn: &Connection) -> Result<(), FrameError> {
+    // Forward under a gateway id, the response is matched on it
+    let gateway_id = conn.pending.lock().unwrap().insert(&req, conn.shared.config.request_timeout);
+    req.id = gateway_id;
+
+    // Forward the request to every central, BLE does its own chunking
+    if conn.shared.transmitter.publish(ToBle::broadcast(Message::Request(req))).await == 0 {
+        println!("transmitter err: no ble receiver");
+
+        // Nothing on the BLE side, fail right away
+        let Some(req) = conn.pending.lock().unwrap().resolve(gateway_id) else {
+            return Ok(());
+        };
+
+        let response = error_response(req, 503, "Service Unavailable", "BLE side is not running".to_string());
+        return conn.send_response(response).await;
+    }
+
+    Ok(())
+}
+
+/// Delivers BLE traffic to the client and times out pending requests.
+async fn write_client(mut subs: Subscriber<ToClient>, conn: Arc<Connection>) -> CloseReason {
+    // How often pending requests are checked for their deadline
+    let mut sweep = interval(PENDING_SWEEP_INTERVAL);
+
+    loop {
+        tokio::select! {
+            ble_msg = subs.recv() => {
+                let m = match ble_msg {
+                    Ok(m) => m,
+                    Err(RecvError::Closed) => return CloseReason::BusClosed,
+                    Err(RecvError::Disconnected) => return CloseReason::TooSlow,
+                    Err(RecvError::Lagged(n)) => {
+                        // Let the client know it missed something
+                        eprintln!("client lagged behind, {} messages dropped", n);
+                        if let Err(e) = conn.send_response(lag_notice(n, &conn.shared.metrics)).await {
+                            return CloseReason::WriteError(e);
+                        }
+                        continue;
+                    }
+                };
+
+                println!("recv ble msg: {:?}", m.raw);
+
+                // Only what this client wants got past the bus filter
+                let res = match m.message.as_deref() {
+                    // Device responses only go to the connection that asked
+                    Some(Message::Response(resp)) => {
+                        let Some(req) = conn.pending.lock().unwrap().resolve(resp.id) else {
+                            continue;
+                        };
+
+                        let mut resp = resp.clone();
+                        resp.id = req.id;
+                        conn.send_response(resp).await
+                    }
+
+                    // In the encoding the client speaks
+                    Some(message) => conn.send_message(message).await,
+                    None => conn.send_frame(m.raw).await,
+                };
+
+                if let Err(e) = res {
+                    return CloseReason::WriteError(e);
+                }
+            }
+
+            _ = sweep.tick() => {
+                let expired = conn.pending.lock().unwrap().expired();
+                for req in expired {
+                    let text = format!("no response from device within {:?}", conn.shared.config.request_timeout);
+                    if let Err(e) = conn.send_response(error_response(req, 504, "Gateway Timeout", text)).await {
+                        return CloseReason::WriteError(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds the response a client gets when its request failed in the gateway.
+fn error_response(req: Pending, code: usize, text: &str, error: String) -> Response {
+    let response = Response::new(
+        req.protocol,
+        req.version,
+        req.id,
+        code,
+        text.to_string(),
+        Some(json!({ "error": error }))
+    );
+
+    match req.correlation {
+        Some(correlation) => response.with_header(CORRELATION_ID, &correlation),
+        None => response,
+    }
+}
+
+/// Body of the `403` a client gets for something its roles do not allow.
+fn forbidden(what: &str, permissions: &Permissions) -> serde_json::Value {
+    json!({ "error": format!("{} is not allowed", what), "roles": permissions.roles })
+}
+
+/// Unsolicited response (id 0) telling a client it missed BLE messages.
+fn lag_notice(dropped: u64, metrics: &Metrics) -> Response {
+    Response::new(
+        PROTO_NAME.to_string(),
+        PROTO_NUMBER.to_string(),
+        0,
+        429,
+        "Lagged".to_string(),
+        Some(json!({ "dropped": dropped, "metrics": metrics.to_json() }))
+    )
+}
+
+/// Addresses a socket client's response to the central whose request it
+/// answers, under the id the central used.
+fn route_response(mut resp: Response, origin: Origin) -> ToBle {
+    resp.id = origin.id;
+
+    ToBle::central(origin.session, Message::Response(resp))
+}
@@ -0,0 +1,61 @@
+//! Handlers the gateway ships with.
+
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::proto::bodies::{Empty, GatewayInfo};
+use crate::proto::msg::Request;
+use crate::proto::types::{Action, Kind};
+use crate::server::metrics::Metrics;
+use crate::server::router::{Handled, Handler, Router, Typed, TypedHandler};
+
+/// Sends the request to the device.
+pub struct ForwardToBle;
+
+impl Handler for ForwardToBle {
+    fn handle(&self, _req: &Request) -> Handled {
+        Handled::Forward
+    }
+}
+
+/// `GET metrics`: counters of the internal bus.
+pub struct MetricsHandler {
+    pub metrics: Arc<Metrics>,
+}
+
+impl TypedHandler for MetricsHandler {
+    type Body = Empty;
+
+    fn handle(&self, req: &Request, _body: Empty) -> Handled {
+        Handled::Local(req.response(200, "OK", Some(self.metrics.to_json())))
+    }
+}
+
+/// `GET gateway`: name and version of the gateway, answered without BLE.
+pub struct GatewayHandler;
+
+impl TypedHandler for GatewayHandler {
+    type Body = Empty;
+
+    fn handle(&self, req: &Request, _body: Empty) -> Handled {
+        let info = GatewayInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        };
+
+        Handled::Local(req.response(200, "OK", Some(json!(info))))
+    }
+}
+
+/// Router with the gateway's own routes, device actions go to BLE.
+pub fn default_router(metrics: Arc<Metrics>) -> Router {
+    let mut router = Router::new();
+
+    router
+        .register_any_kind(Action::Get, Arc::new(ForwardToBle))
+        .register_any_kind(Action::Set, Arc::new(ForwardToBle))
+        .register(Action::Get, Kind::Metrics, Arc::new(Typed(MetricsHandler { metrics })))
+        .register(Action::Get, Kind::Gateway, Arc::new(Typed(GatewayHandler)));
+
+    router
+}
@@ -1,107 +1,780 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::{io, usize};
-use tokio_util::codec::{Decoder, Encoder};
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC};
+use futures::{Stream, StreamExt};
+use std::io;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::AsyncRead;
+use tokio::sync::mpsc;
+use tokio::time::{self, Sleep};
+use tokio_util::codec::{Decoder, Encoder, FramedRead};
 
 /// Maximum frame payload size (tune as needed).
 pub const MAX_FRAME_SIZE: usize = 64 * 1024; // 64KB
 
-/// codec: reads 2-byte start-of-frame, then 2-byte BE length, then payload.
+/// Maximum payload size of extended-length frames.
+pub const MAX_EXTENDED_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+/// How long a frame may stay incomplete before it is treated as garbage.
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// First byte of every start-of-frame marker.
+const SOF_LEAD: u8 = 0xff;
+
+/// Second byte of the marker, tells which trailer the frame carries.
+const SOF_PLAIN: u8 = 0xff;
+const SOF_CRC16: u8 = 0xfe;
+const SOF_CRC32: u8 = 0xfd;
+
+const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Start-of-frame marker plus length field.
+const PREAMBLE_SIZE: usize = 4;
+
+/// Length field value announcing a 4-byte BE length right after it.
+const EXTENDED_LEN: u16 = 0xffff;
+
+/// Size of the extended length field.
+const EXTENDED_LEN_SIZE: usize = 4;
+
+/// Frame header following the length field, counted by the length.
+const HEADER_SIZE: usize = 2;
+
+/// Frame header version written by the encoder, the only one decoded.
+pub const FRAME_VERSION: u8 = 1;
+
+// Header byte 0: version in the high nibble, flags in the low one.
+const FLAG_COMPRESSED: u8 = 0x01;
+const FLAG_FRAGMENT: u8 = 0x02;
+const FLAG_BINARY: u8 = 0x04;
+const FLAGS_RESERVED: u8 = 0x08;
+
+// Header byte 1: priority in the low 3 bits, the rest is reserved.
+const PRIORITY_MASK: u8 = 0x07;
+
+#[derive(Error, Debug)]
+pub enum FrameError {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("no start of frame in {0} bytes")]
+    NoStartOfFrame(usize),
+
+    #[error("frame length {0} is shorter than the frame header")]
+    TooShort(usize),
+
+    #[error("frame length {len} exceeds max {max}")]
+    TooLarge { len: usize, max: usize },
+
+    #[error("frame of {len} bytes incomplete after {waited:?}")]
+    Stalled { len: usize, waited: Duration },
+
+    #[error("corrupt frame of {len} bytes: crc {actual:#x}, expected {expected:#x}")]
+    Corrupt { len: usize, expected: u32, actual: u32 },
+
+    #[error("unsupported frame header {0:02x?}")]
+    UnsupportedHeader([u8; HEADER_SIZE]),
+}
+
+/// The two bytes after the length field.
+///
+/// ```text
+/// byte 0: vvvv rBFC   v = version, B = binary, F = fragment, C = compressed, r = reserved
+/// byte 1: rrrr rPPP   P = priority (0 lowest, 7 highest)
+/// ```
+///
+/// Reserved bits must be zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    /// Payload is compressed. No compression is implemented yet, so
+    /// frames with this bit are rejected.
+    pub compressed: bool,
+    /// Payload is one piece of a larger message. There is no reassembly
+    /// yet, so frames with this bit are rejected too.
+    pub fragment: bool,
+    /// Payload is a message in the binary encoding rather than text.
+    pub binary: bool,
+    pub priority: u8,
+}
+
+impl Default for FrameHeader {
+    fn default() -> Self {
+        Self {
+            version: FRAME_VERSION,
+            compressed: false,
+            fragment: false,
+            binary: false,
+            priority: 0,
+        }
+    }
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut b0 = (self.version & 0x0f) << 4;
+        if self.compressed {
+            b0 |= FLAG_COMPRESSED;
+        }
+        if self.fragment {
+            b0 |= FLAG_FRAGMENT;
+        }
+        if self.binary {
+            b0 |= FLAG_BINARY;
+        }
+
+        [b0, self.priority & PRIORITY_MASK]
+    }
+
+    /// Parses a header, rejecting anything this codec does not understand.
+    pub fn from_bytes(b: [u8; HEADER_SIZE]) -> Result<Self, FrameError> {
+        let header = Self {
+            version: b[0] >> 4,
+            compressed: b[0] & FLAG_COMPRESSED != 0,
+            fragment: b[0] & FLAG_FRAGMENT != 0,
+            binary: b[0] & FLAG_BINARY != 0,
+            priority: b[1] & PRIORITY_MASK,
+        };
+
+        if header.version != FRAME_VERSION
+            || header.compressed
+            || header.fragment
+            || b[0] & FLAGS_RESERVED != 0
+            || b[1] & !PRIORITY_MASK != 0
+        {
+            return Err(FrameError::UnsupportedHeader(b));
+        }
+
+        Ok(header)
+    }
+}
+
+/// A decoded frame.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Bytes,
+    /// Trailer the frame came with, or goes out with. `None` leaves it to
+    /// the encoder.
+    pub checksum: Option<Checksum>,
+}
+
+impl Frame {
+    pub fn new(payload: Bytes) -> Self {
+        Self { header: FrameHeader::default(), payload, checksum: None }
+    }
+}
+
+/// Integrity trailer appended to encoded frames.
+///
+/// The second byte of the start-of-frame marker tells the decoder which one
+/// a frame uses, so every variant can be read regardless of this setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checksum {
+    None,
+    /// CRC-16/IBM-3740 (a.k.a. CCITT-FALSE), 2 bytes BE.
+    Crc16,
+    /// CRC-32/ISO-HDLC, 4 bytes BE.
+    Crc32,
+}
+
+impl Checksum {
+    fn from_marker(b: u8) -> Option<Self> {
+        match b {
+            SOF_PLAIN => Some(Checksum::None),
+            SOF_CRC16 => Some(Checksum::Crc16),
+            SOF_CRC32 => Some(Checksum::Crc32),
+            _ => None,
+        }
+    }
+
+    fn marker(self) -> [u8; 2] {
+        match self {
+            Checksum::None => [SOF_LEAD, SOF_PLAIN],
+            Checksum::Crc16 => [SOF_LEAD, SOF_CRC16],
+            Checksum::Crc32 => [SOF_LEAD, SOF_CRC32],
+        }
+    }
+
+    fn trailer_len(self) -> usize {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => 2,
+            Checksum::Crc32 => 4,
+        }
+    }
+
+    fn compute(self, data: &[u8]) -> u32 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => CRC16.checksum(data) as u32,
+            Checksum::Crc32 => CRC32.checksum(data),
+        }
+    }
+
+    /// Reads the trailer stored at the start of `b`.
+    fn read(self, b: &[u8]) -> u32 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Crc16 => u16::from_be_bytes([b[0], b[1]]) as u32,
+            Checksum::Crc32 => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+        }
+    }
+
+    fn write(self, value: u32, dst: &mut BytesMut) {
+        match self {
+            Checksum::None => {}
+            Checksum::Crc16 => dst.put_u16(value as u16),
+            Checksum::Crc32 => dst.put_u32(value),
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Checksum::None),
+            "crc16" => Ok(Checksum::Crc16),
+            "crc32" => Ok(Checksum::Crc32),
+            _ => Err("expected none, crc16 or crc32".to_string()),
+        }
+    }
+}
+
+/// Reported every time the decoder throws bytes away to find the next frame.
+#[derive(Debug)]
+pub struct ResyncEvent {
+    /// Number of bytes discarded.
+    pub dropped: usize,
+    /// Why they were discarded.
+    pub cause: FrameError,
+}
+
+/// Counters shared between the codec and whoever wants to look at them.
+#[derive(Debug, Default)]
+pub struct CodecStats {
+    resyncs: AtomicU64,
+    dropped_bytes: AtomicU64,
+    corrupt_frames: AtomicU64,
+}
+
+impl CodecStats {
+    pub fn resyncs(&self) -> u64 {
+        self.resyncs.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn corrupt_frames(&self) -> u64 {
+        self.corrupt_frames.load(Ordering::Relaxed)
+    }
+
+    /// Adds the counts of `other` to these.
+    pub fn add(&self, other: &CodecStats) {
+        self.resyncs.fetch_add(other.resyncs(), Ordering::Relaxed);
+        self.dropped_bytes.fetch_add(other.dropped_bytes(), Ordering::Relaxed);
+        self.corrupt_frames.fetch_add(other.corrupt_frames(), Ordering::Relaxed);
+    }
+}
+
+/// codec: reads 2-byte start-of-frame, then 2-byte BE length, then the
+/// 2-byte `FrameHeader` and payload (both counted by the length), then an
+/// optional CRC trailer covering everything before it.
+///
+/// A length of `0xffff` is an escape: the real length follows as 4 bytes BE.
+/// Payloads up to `max_frame` use the short form, bigger ones up to
+/// `max_extended_frame` the extended form.
+///
+/// Bytes that do not belong to a frame are skipped until the next
+/// start-of-frame marker. A frame that gets no more bytes for longer than
+/// the stall timeout is abandoned so it cannot hold back the frames after
+/// it, `FrameReader` makes sure that happens even if the peer goes quiet.
+/// Frames whose CRC does not match are dropped and reported as
+/// `FrameError::Corrupt` through the resync events, frames with a header
+/// the codec does not understand as `FrameError::UnsupportedHeader`.
 pub struct TwoByteLenSkipReserved {
     max_frame: usize,
+    max_extended_frame: usize,
+    checksum: Checksum,
+    stall_timeout: Duration,
+    /// When the incomplete frame at the front last grew, and to how many
+    /// buffered bytes.
+    progress: Option<(Instant, usize)>,
+    stats: Arc<CodecStats>,
+    events: Option<mpsc::UnboundedSender<ResyncEvent>>,
 }
 
 impl TwoByteLenSkipReserved {
     pub fn new(max_frame: usize) -> Self {
-        Self { max_frame }
+        Self {
+            max_frame,
+            max_extended_frame: MAX_EXTENDED_FRAME_SIZE,
+            checksum: Checksum::None,
+            stall_timeout: DEFAULT_STALL_TIMEOUT,
+            progress: None,
+            stats: Arc::new(CodecStats::default()),
+            events: None,
+        }
+    }
+
+    /// Sets the payload limit of extended-length frames, 0 disables them.
+    pub fn with_max_extended_frame(mut self, max: usize) -> Self {
+        self.max_extended_frame = max;
+        self
+    }
+
+    /// Sets the trailer added to encoded frames.
+    pub fn with_checksum(mut self, checksum: Checksum) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Sets how long an incomplete frame is waited for.
+    pub fn with_stall_timeout(mut self, timeout: Duration) -> Self {
+        self.stall_timeout = timeout;
+        self
+    }
+
+    /// Sends a `ResyncEvent` to `tx` every time bytes are discarded.
+    pub fn with_resync_events(mut self, tx: mpsc::UnboundedSender<ResyncEvent>) -> Self {
+        self.events = Some(tx);
+        self
+    }
+
+    /// When the incomplete frame at the front is abandoned unless more of
+    /// it arrives, `None` if there is none.
+    pub fn stall_deadline(&self) -> Option<Instant> {
+        self.progress.map(|(at, _)| at + self.stall_timeout)
+    }
+
+    /// Resync counters, still readable after the codec is moved into a `Framed`.
+    pub fn stats(&self) -> Arc<CodecStats> {
+        self.stats.clone()
+    }
+
+    /// Drops `count` bytes from the front of `src` and reports it.
+    fn discard(&mut self, src: &mut BytesMut, count: usize, cause: FrameError) {
+        src.advance(count);
+        self.progress = None;
+
+        self.stats.resyncs.fetch_add(1, Ordering::Relaxed);
+        self.stats.dropped_bytes.fetch_add(count as u64, Ordering::Relaxed);
+        if let FrameError::Corrupt { .. } = cause {
+            self.stats.corrupt_frames.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(tx) = &self.events {
+            let _ = tx.send(ResyncEvent { dropped: count, cause });
+        }
+    }
+
+    /// Skips anything before the next start-of-frame marker.
+    /// Returns false if there is no marker in the buffer yet.
+    fn seek_start(&mut self, src: &mut BytesMut) -> bool {
+        let is_marker = |w: &[u8]| w[0] == SOF_LEAD && Checksum::from_marker(w[1]).is_some();
+
+        match src.windows(2).position(is_marker) {
+            Some(0) => true,
+            Some(pos) => {
+                self.discard(src, pos, FrameError::NoStartOfFrame(pos));
+                true
+            }
+            None => {
+                // Keep a trailing 0xff, it may be the first half of a marker.
+                let keep = usize::from(src.last() == Some(&SOF_LEAD));
+                let garbage = src.len() - keep;
+                if garbage > 0 {
+                    self.discard(src, garbage, FrameError::NoStartOfFrame(garbage));
+                }
+                false
+            }
+        }
     }
 }
 
 impl Decoder for TwoByteLenSkipReserved {
-    type Item = Bytes; // payload bytes (without len/reserved)
-    type Error = io::Error;
+    type Item = Frame;
+    type Error = FrameError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Need at least 2 bytes for len
-        if src.len() < 2 {
-            return Ok(None);
-        }
+        loop {
+            if !self.seek_start(src) || src.len() < PREAMBLE_SIZE {
+                return Ok(None);
+            }
 
-        // Peek initial 2 bytes until start of frame is seen
-        // don't advance; read from the buffer directly
-        if src[0] != 0xff || src[1] != 0xff {
-            return Ok(None);
-        }
+            // seek_start only stops on known markers
+            let checksum = Checksum::from_marker(src[1]).unwrap_or(Checksum::None);
 
-        // Peek 2 bytes for length (big endian)
-        let len = {
-            // don't advance; read from the buffer directly
-            let b2 = src[2];
-            let b3 = src[3];
-            u16::from_be_bytes([b2, b3]) as usize
-        };
+            // Peek 2 bytes for length (big endian), don't advance yet
+            let short_len = u16::from_be_bytes([src[2], src[3]]);
+
+            let (preamble, len, max) = if short_len == EXTENDED_LEN {
+                if src.len() < PREAMBLE_SIZE + EXTENDED_LEN_SIZE {
+                    return Ok(None);
+                }
+                let len = u32::from_be_bytes([src[4], src[5], src[6], src[7]]) as usize;
+                (PREAMBLE_SIZE + EXTENDED_LEN_SIZE, len, self.max_extended_frame)
+            } else {
+                (PREAMBLE_SIZE, short_len as usize, self.max_frame)
+            };
+
+            // Not a real frame, skip the marker and look for the next one
+            if len < HEADER_SIZE {
+                self.discard(src, 1, FrameError::TooShort(len));
+                continue;
+            }
+
+            if len - HEADER_SIZE > max {
+                self.discard(src, 1, FrameError::TooLarge { len: len - HEADER_SIZE, max });
+                continue;
+            }
+
+            if src.len() < preamble + HEADER_SIZE {
+                return Ok(None);
+            }
+
+            // Checking the header early also catches markers that are really
+            // a stray 0xff followed by the start of the next frame.
+            let header = match FrameHeader::from_bytes([src[preamble], src[preamble + 1]]) {
+                Ok(h) => h,
+                Err(e) => {
+                    self.discard(src, 1, e);
+                    continue;
+                }
+            };
+
+            let total_frame = preamble + len + checksum.trailer_len();
+
+            // Wait until whole frame arrives, but not forever. The clock
+            // starts over whenever more of it shows up.
+            if src.len() < total_frame {
+                let now = Instant::now();
+                let since = match self.progress {
+                    Some((at, seen)) if src.len() <= seen => at,
+                    _ => now,
+                };
+                self.progress = Some((since, src.len()));
+                let waited = now.duration_since(since);
+
+                if waited >= self.stall_timeout {
+                    self.discard(src, 1, FrameError::Stalled { len, waited });
+                    continue;
+                }
+
+                // The buffer grows as bytes arrive, not by what the length claims
+                return Ok(None);
+            }
+
+            self.progress = None;
+
+            let body_end = preamble + len;
+            let expected = checksum.read(&src[body_end..total_frame]);
+            let actual = checksum.compute(&src[..body_end]);
 
-        // total frame = 2 (len field itself) + len
-        let total_frame = 4usize + len;
+            if actual != expected {
+                // The length may be what got corrupted, so only skip the marker
+                self.discard(src, 1, FrameError::Corrupt { len, expected, actual });
+                continue;
+            }
 
-        // Check max frame length
-        if len > self.max_frame {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("frame length {} exceeds max {}", len, self.max_frame),
-            ));
+            // We have the full frame, drop start of frame, length, header and trailer
+            let mut frame = src.split_to(total_frame);
+            frame.advance(preamble + HEADER_SIZE);
+            frame.truncate(len - HEADER_SIZE);
+
+            // the remaining bytes are the payload
+            return Ok(Some(Frame { header, payload: frame.freeze(), checksum: Some(checksum) }));
         }
+    }
+}
+
+/// Frames from a `FramedRead`, abandoning stalled frames on time.
+///
+/// `FramedRead` only runs the decoder when bytes arrive, so a frame that
+/// never completes would hold back the frames buffered behind it until the
+/// peer writes again. This runs the decoder once more when it is due.
+pub struct FrameReader<R> {
+    reader: FramedRead<R, TwoByteLenSkipReserved>,
+    stall: Pin<Box<Sleep>>,
+    /// Set after the timer, `FramedRead` does not know about frames decoded
+    /// behind its back and would wait for bytes before the next one.
+    drain: bool,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: FramedRead<R, TwoByteLenSkipReserved>) -> Self {
+        Self { reader, stall: Box::pin(time::sleep(Duration::ZERO)), drain: false }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for FrameReader<R> {
+    type Item = Result<Frame, FrameError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+
+        loop {
+            if this.drain {
+                let mut buf = std::mem::take(this.reader.read_buffer_mut());
+                let decoded = this.reader.decoder_mut().decode(&mut buf);
+                *this.reader.read_buffer_mut() = buf;
+
+                match decoded {
+                    Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                    Ok(None) => this.drain = false,
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
 
-        // Wait until whole frame arrives
-        if src.len() < total_frame {
-            return Ok(None);
+            if let Poll::Ready(frame) = this.reader.poll_next_unpin(cx) {
+                return Poll::Ready(frame);
+            }
+
+            // Whatever the decoder is waiting on now, it may have just started
+            let Some(deadline) = this.reader.decoder().stall_deadline() else {
+                return Poll::Pending;
+            };
+
+            this.stall.as_mut().reset(time::Instant::from_std(deadline));
+            if this.stall.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            this.drain = true;
         }
+    }
+}
+
+impl Encoder<Frame> for TwoByteLenSkipReserved {
+    type Error = FrameError;
 
-        // We have the full frame. Remove the length field first.
-        // split_to(total_frame) will give us a BytesMut containing len field + payload (including reserved).
-        let mut frame = src.split_to(total_frame);
+    fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let payload_len = item.payload.len();
 
-        // Advance past length field (2 bytes)
-        frame.advance(2);
+        // length field includes the 2 header bytes
+        let len = HEADER_SIZE + payload_len;
+        let extended = payload_len > self.max_frame || len >= EXTENDED_LEN as usize;
 
-        // Now frame contains: reserved(2) + payload(len-2)
-        if frame.len() < 2 {
-            // malformed: length claims there's reserved bytes but they're not present
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "missing reserved bytes"));
+        if extended && (payload_len > self.max_extended_frame || len > u32::MAX as usize) {
+            let max = self.max_frame.max(self.max_extended_frame);
+            return Err(FrameError::TooLarge { len: payload_len, max });
         }
 
-        // discard reserved bytes
-        frame.advance(2);
+        // Reserve space and append
+        let checksum = item.checksum.unwrap_or(self.checksum);
+        let preamble = if extended { PREAMBLE_SIZE + EXTENDED_LEN_SIZE } else { PREAMBLE_SIZE };
+        dst.reserve(preamble + len + checksum.trailer_len());
+        let start = dst.len();
+        dst.put_slice(&checksum.marker());
+        if extended {
+            dst.put_u16(EXTENDED_LEN);
+            dst.put_u32(len as u32);
+        } else {
+            dst.put_u16(len as u16);
+        }
+        dst.put_slice(&item.header.to_bytes());
+        dst.put_slice(&item.payload);
 
-        // the remaining bytes are the payload
-        let payload = frame.freeze(); // Bytes
+        let crc = checksum.compute(&dst[start..]);
+        checksum.write(crc, dst);
 
-        Ok(Some(payload))
+        Ok(())
     }
 }
 
 impl Encoder<Bytes> for TwoByteLenSkipReserved {
-    type Error = io::Error;
+    type Error = FrameError;
 
+    /// Encodes `item` as the payload of a frame with the default header.
     fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let payload_len = item.len();
+        self.encode(Frame::new(item), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn encoded(codec: &mut TwoByteLenSkipReserved, payload: &'static [u8]) -> BytesMut {
+        let mut buf = BytesMut::new();
+        codec.encode(Bytes::from_static(payload), &mut buf).unwrap();
+        buf
+    }
+
+    /// A marker and header announcing 100 bytes that never come.
+    fn bogus_frame() -> BytesMut {
+        BytesMut::from(&[SOF_LEAD, SOF_PLAIN, 0x00, 100, FRAME_VERSION << 4, 0x00][..])
+    }
+
+    #[test]
+    fn skips_garbage_before_a_frame() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE).with_resync_events(tx);
+
+        let mut buf = BytesMut::from(&b"xyz"[..]);
+        buf.extend_from_slice(&encoded(&mut codec, b"hello"));
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&frame.payload[..], b"hello");
+        assert!(buf.is_empty());
+        assert_eq!(codec.stats().dropped_bytes(), 3);
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.dropped, 3);
+        assert!(matches!(event.cause, FrameError::NoStartOfFrame(3)));
+    }
+
+    #[test]
+    fn keeps_a_trailing_marker_half() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
+        let frame = encoded(&mut codec, b"hello");
+
+        let mut buf = BytesMut::from(&[0x01, SOF_LEAD][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], &[SOF_LEAD]);
+
+        buf.extend_from_slice(&frame[1..]);
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"hello");
+    }
+
+    #[test]
+    fn corrupt_frame_is_dropped() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE).with_checksum(Checksum::Crc16);
+
+        let mut buf = encoded(&mut codec, b"hello");
+        buf[7] ^= 0x20;
+        buf.extend_from_slice(&encoded(&mut codec, b"world"));
+
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"world");
+        assert_eq!(codec.stats().corrupt_frames(), 1);
+    }
+
+    #[test]
+    fn frames_keep_their_checksum() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
+
+        let mut frame = Frame::new(Bytes::from_static(b"hello"));
+        frame.checksum = Some(Checksum::Crc32);
+        let mut buf = BytesMut::new();
+        codec.encode(frame, &mut buf).unwrap();
+        assert_eq!(&buf[..2], &Checksum::Crc32.marker());
+
+        let frame = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.checksum, Some(Checksum::Crc32));
+        assert_eq!(&encoded(&mut codec, b"hello")[..2], &Checksum::None.marker());
+    }
+
+    #[test]
+    fn stalled_frame_is_abandoned() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE).with_stall_timeout(Duration::from_millis(20));
+
+        let mut buf = bogus_frame();
+        buf.extend_from_slice(&encoded(&mut codec, b"hello"));
+
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(codec.stall_deadline().is_some());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"hello");
+        assert!(codec.stall_deadline().is_none());
+    }
+
+    #[test]
+    fn progress_restarts_the_stall_clock() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE).with_stall_timeout(Duration::from_millis(60));
+        let frame = encoded(&mut codec, b"slowly but surely");
 
-        if payload_len > self.max_frame {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("payload length {} exceeds max {}", payload_len, self.max_frame),
-            ));
+        let mut buf = BytesMut::new();
+        for chunk in frame.chunks(4) {
+            buf.extend_from_slice(chunk);
+            if buf.len() < frame.len() {
+                assert!(codec.decode(&mut buf).unwrap().is_none());
+                std::thread::sleep(Duration::from_millis(25));
+            }
         }
 
-        // length field includes the 2 reserved bytes
-        let len_field = payload_len as u16;
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"slowly but surely");
+        assert_eq!(codec.stats().resyncs(), 0);
+    }
 
-        // Reserve space and append
-        dst.reserve(2 + 2 + payload_len);
-        dst.put_slice(&[0xffu8, 0xffu8]);
-        dst.put_slice(&len_field.to_be_bytes());
-        //dst.put_slice(&[0u8, 0u8]); // reserved bytes
-        dst.put_slice(&item);
+    #[test]
+    fn header_round_trip() {
+        let header = FrameHeader { binary: true, priority: 5, ..FrameHeader::default() };
+        assert_eq!(FrameHeader::from_bytes(header.to_bytes()).unwrap(), header);
+    }
 
-        Ok(())
+    #[test]
+    fn unsupported_header_is_rejected() {
+        let compressed = FrameHeader { compressed: true, ..FrameHeader::default() }.to_bytes();
+        let fragment = FrameHeader { fragment: true, ..FrameHeader::default() }.to_bytes();
+        let reserved = [(FRAME_VERSION << 4) | FLAGS_RESERVED, 0];
+        let version = [2 << 4, 0];
+
+        for b in [compressed, fragment, reserved, version] {
+            assert!(matches!(FrameHeader::from_bytes(b), Err(FrameError::UnsupportedHeader(_))));
+        }
+    }
+
+    #[test]
+    fn fragment_frame_is_skipped() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
+
+        let fragment = Frame { header: FrameHeader { fragment: true, ..FrameHeader::default() }, payload: Bytes::from_static(b"part"), checksum: None };
+        let mut buf = BytesMut::new();
+        codec.encode(fragment, &mut buf).unwrap();
+        buf.extend_from_slice(&encoded(&mut codec, b"whole"));
+
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"whole");
+    }
+
+    #[test]
+    fn extended_frame_round_trip() {
+        let mut codec = TwoByteLenSkipReserved::new(8).with_max_extended_frame(64);
+
+        let mut buf = encoded(&mut codec, b"longer than eight bytes");
+        assert_eq!(u16::from_be_bytes([buf[2], buf[3]]), EXTENDED_LEN);
+        assert_eq!(&codec.decode(&mut buf).unwrap().unwrap().payload[..], b"longer than eight bytes");
+
+        let too_large = Bytes::from(vec![0u8; 65]);
+        assert!(matches!(codec.encode(too_large, &mut buf), Err(FrameError::TooLarge { len: 65, max: 64 })));
+    }
+
+    #[test]
+    fn claimed_length_is_not_allocated_up_front() {
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
+
+        // Extended frame announcing 16 MB
+        let mut buf = BytesMut::from(&[SOF_LEAD, SOF_PLAIN, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, FRAME_VERSION << 4, 0x00][..]);
+        assert!(codec.decode(&mut buf).unwrap().is_none());
+        assert!(buf.capacity() < 1024);
+    }
+
+    #[tokio::test]
+    async fn reader_abandons_stalled_frames_without_new_bytes() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE).with_stall_timeout(Duration::from_millis(50));
+
+        let mut buf = bogus_frame();
+        buf.extend_from_slice(&encoded(&mut codec, b"hello"));
+        buf.extend_from_slice(&encoded(&mut codec, b"world"));
+        client.write_all(&buf).await.unwrap();
+
+        // The client keeps the connection open and waits for its replies
+        let mut frames = FrameReader::new(FramedRead::new(server, codec));
+        for expected in [&b"hello"[..], b"world"] {
+            let frame = time::timeout(Duration::from_secs(1), frames.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(&frame.payload[..], expected);
+        }
     }
 }
@@ -0,0 +1,121 @@
+//! Counters about the internal bus and the client links, for anyone who
+//! wants to look.
+
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::bus::fanout::FanoutStats;
+use crate::server::mcodec::CodecStats;
+
+pub struct Metrics {
+    /// BLE traffic fanned out to socket clients.
+    pub client_bus: Arc<FanoutStats>,
+    /// Server traffic going to the BLE adapter.
+    pub ble_bus: Arc<FanoutStats>,
+    /// Messages from BLE lost before reaching the dispatcher.
+    pub ble_lagged: AtomicU64,
+    /// Frame codec counters of open connections, by peer.
+    codecs: Mutex<HashMap<u64, (String, Arc<CodecStats>)>>,
+    next_codec: AtomicU64,
+    /// Frame codec counters of connections that are gone, summed up.
+    closed_codecs: CodecStats,
+}
+
+/// Keeps the codec counters of a connection in the metrics, and adds them
+/// to the totals once dropped.
+pub struct TrackedCodec {
+    metrics: Arc<Metrics>,
+    id: u64,
+}
+
+impl Drop for TrackedCodec {
+    fn drop(&mut self) {
+        if let Some((_, stats)) = self.metrics.codecs.lock().unwrap().remove(&self.id) {
+            self.metrics.closed_codecs.add(&stats);
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new(client_bus: Arc<FanoutStats>, ble_bus: Arc<FanoutStats>) -> Self {
+        Self {
+            client_bus,
+            ble_bus,
+            ble_lagged: AtomicU64::new(0),
+            codecs: Mutex::new(HashMap::new()),
+            next_codec: AtomicU64::new(0),
+            closed_codecs: CodecStats::default(),
+        }
+    }
+
+    /// Reports the codec counters of the connection to `peer` until the
+    /// returned guard is dropped.
+    pub fn track_codec(self: &Arc<Self>, peer: String, stats: Arc<CodecStats>) -> TrackedCodec {
+        let id = self.next_codec.fetch_add(1, Ordering::Relaxed);
+        self.codecs.lock().unwrap().insert(id, (peer, stats));
+        TrackedCodec { metrics: self.clone(), id }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let bus = |s: &FanoutStats| json!({
+            "published": s.published(),
+            "dropped": s.dropped(),
+            "disconnected": s.disconnected(),
+            "blocked": s.blocked(),
+        });
+
+        let totals = CodecStats::default();
+        totals.add(&self.closed_codecs);
+        let mut connections: Vec<(u64, Value)> = self.codecs.lock().unwrap().iter()
+            .map(|(id, (peer, stats))| {
+                totals.add(stats);
+                (*id, json!({
+                    "peer": peer,
+                    "resyncs": stats.resyncs(),
+                    "dropped_bytes": stats.dropped_bytes(),
+                    "corrupt_frames": stats.corrupt_frames(),
+                }))
+            })
+            .collect();
+        connections.sort_by_key(|(id, _)| *id);
+
+        json!({
+            "client_bus": bus(&self.client_bus),
+            "ble_bus": bus(&self.ble_bus),
+            "ble_lagged": self.ble_lagged.load(Ordering::Relaxed),
+            "frames": {
+                "resyncs": totals.resyncs(),
+                "dropped_bytes": totals.dropped_bytes(),
+                "corrupt_frames": totals.corrupt_frames(),
+                "connections": connections.into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::mcodec::{TwoByteLenSkipReserved, MAX_FRAME_SIZE};
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn closed_connections_stay_in_the_totals() {
+        let metrics = Arc::new(Metrics::new(Arc::default(), Arc::default()));
+        let mut codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
+        let tracked = metrics.track_codec("peer".to_string(), codec.stats());
+
+        codec.decode(&mut BytesMut::from(&b"junk"[..])).unwrap();
+        let frames = &metrics.to_json()["frames"];
+        assert_eq!(frames["dropped_bytes"], 4);
+        assert_eq!(frames["connections"][0]["peer"], "peer");
+
+        drop(tracked);
+        let frames = &metrics.to_json()["frames"];
+        assert_eq!(frames["dropped_bytes"], 4);
+        assert_eq!(frames["connections"].as_array().unwrap().len(), 0);
+    }
+}
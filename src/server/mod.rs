@@ -1,5 +1,19 @@
+#[allow(clippy::module_inception)]
 pub mod server;
+pub mod config;
+pub mod connection;
+pub mod handlers;
 pub mod mcodec;
+pub mod metrics;
+pub mod origin;
 pub mod peer;
+pub mod pending;
+pub mod policy;
+pub mod router;
+pub mod socket;
+pub mod subscriptions;
+pub mod tcp;
+pub mod websocket;
 
-pub use server::run;
\ No newline at end of file
+pub use config::{ServerConfig, SocketConfig, TcpConfig, TlsConfig};
+pub use server::run;
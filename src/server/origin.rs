@@ -0,0 +1,119 @@
+//! Remembers which BLE central sent each request, so the reply can be
+//! routed back to it alone.
+//!
+//! Requests coming from BLE get a gateway-wide id before socket clients see
+//! them, since two centrals may well pick the same id. The response carries
+//! that id back, which is swapped for the central's own id again.
+
+use bluer::Address;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a request from BLE waits for its response.
+pub const ORIGIN_TTL: Duration = Duration::from_secs(60);
+
+/// Where a BLE request came from.
+#[derive(Debug, Clone, Copy)]
+pub struct Origin {
+    pub session: Address,
+    /// Id the central used.
+    pub id: usize,
+    created: Instant,
+}
+
+struct Table {
+    next_id: usize,
+    origins: HashMap<usize, Origin>,
+}
+
+pub struct Origins {
+    ttl: Duration,
+    table: Mutex<Table>,
+}
+
+impl Default for Origins {
+    fn default() -> Self {
+        Self::new(ORIGIN_TTL)
+    }
+}
+
+impl Origins {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            table: Mutex::new(Table { next_id: 1, origins: HashMap::new() }),
+        }
+    }
+
+    /// Records a request from `session`, returns the id to forward it with.
+    pub fn register(&self, session: Address, id: usize) -> usize {
+        let mut table = self.table.lock().unwrap();
+        let now = Instant::now();
+
+        // Forget requests nobody answered
+        table.origins.retain(|_, o| now.duration_since(o.created) < self.ttl);
+
+        let gateway_id = table.next_id;
+        table.next_id = table.next_id.wrapping_add(1).max(1);
+        table.origins.insert(gateway_id, Origin { session, id, created: now });
+
+        gateway_id
+    }
+
+    /// How long a request from BLE waits for its response.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Looks up and forgets the origin of the request with `gateway_id`,
+    /// `None` if it is unknown or past the ttl.
+    pub fn take(&self, gateway_id: usize) -> Option<Origin> {
+        let origin = self.table.lock().unwrap().origins.remove(&gateway_id)?;
+        (origin.created.elapsed() < self.ttl).then_some(origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CENTRAL_A: Address = Address([0, 0, 0, 0, 0, 1]);
+    const CENTRAL_B: Address = Address([0, 0, 0, 0, 0, 2]);
+
+    #[test]
+    fn same_id_from_two_centrals_gets_two_gateway_ids() {
+        let origins = Origins::default();
+        let a = origins.register(CENTRAL_A, 7);
+        let b = origins.register(CENTRAL_B, 7);
+        assert_ne!(a, b);
+
+        let origin = origins.take(b).unwrap();
+        assert_eq!((origin.session, origin.id), (CENTRAL_B, 7));
+        let origin = origins.take(a).unwrap();
+        assert_eq!((origin.session, origin.id), (CENTRAL_A, 7));
+    }
+
+    #[test]
+    fn take_forgets_the_origin() {
+        let origins = Origins::default();
+        let id = origins.register(CENTRAL_A, 3);
+        assert_eq!(origins.take(id).map(|o| o.id), Some(3));
+        assert!(origins.take(id).is_none());
+        assert!(origins.take(id + 1).is_none());
+    }
+
+    #[test]
+    fn origins_past_the_ttl_are_gone() {
+        let origins = Origins::new(Duration::from_millis(20));
+        let late = origins.register(CENTRAL_A, 1);
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(origins.take(late).is_none());
+
+        // Registering prunes what nobody answered in time
+        let pruned = origins.register(CENTRAL_A, 2);
+        std::thread::sleep(Duration::from_millis(30));
+        origins.register(CENTRAL_B, 3);
+        assert!(!origins.table.lock().unwrap().origins.contains_key(&pruned));
+    }
+}
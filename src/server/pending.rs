@@ -0,0 +1,150 @@
+//! Requests a connection forwarded to BLE and is still waiting on.
+//!
+//! Requests go out with a gateway-wide id, so the device's responses can
+//! be told apart even when two clients use the same ids. The client's own
+//! id is put back before the response is delivered.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::proto::headers::CORRELATION_ID;
+use crate::proto::msg::Request;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// A gateway id no pending request gets, for requests whose response
+/// nobody waits for. Client ids are never sent to BLE, they could be
+/// mistaken for the gateway id of another connection's request.
+pub fn reserve_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// A request waiting for its response.
+#[derive(Debug)]
+pub struct Pending {
+    pub protocol: String,
+    pub version: String,
+    /// Id the client used.
+    pub id: usize,
+    /// Echoed back if the gateway answers in the device's place.
+    pub correlation: Option<String>,
+    pub deadline: Instant,
+}
+
+/// Pending requests of one connection, keyed by gateway id.
+#[derive(Debug, Default)]
+pub struct PendingTable {
+    requests: HashMap<usize, Pending>,
+}
+
+impl PendingTable {
+    /// Tracks `req`, returns the id to forward it with.
+    pub fn insert(&mut self, req: &Request, timeout: Duration) -> usize {
+        let gateway_id = reserve_id();
+
+        self.requests.insert(gateway_id, Pending {
+            protocol: req.protocol.clone(),
+            version: req.version.clone(),
+            id: req.id,
+            correlation: req.headers.get(CORRELATION_ID).map(str::to_string),
+            deadline: Instant::now() + timeout,
+        });
+
+        gateway_id
+    }
+
+    pub fn contains(&self, gateway_id: usize) -> bool {
+        self.requests.contains_key(&gateway_id)
+    }
+
+    /// Stops tracking the request forwarded as `gateway_id`.
+    pub fn resolve(&mut self, gateway_id: usize) -> Option<Pending> {
+        self.requests.remove(&gateway_id)
+    }
+
+    /// Removes and returns the requests past their deadline.
+    pub fn expired(&mut self) -> Vec<Pending> {
+        let now = Instant::now();
+        let ids: Vec<usize> = self
+            .requests
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.iter().filter_map(|id| self.requests.remove(id)).collect()
+    }
+
+    /// Removes and returns every pending request.
+    pub fn drain(&mut self) -> Vec<Pending> {
+        self.requests.drain().map(|(_, p)| p).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::msg::{PROTO_NAME, PROTO_NUMBER};
+    use crate::proto::types::{Action, Kind};
+
+    fn request(id: usize) -> Request {
+        Request::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), id, Action::Get, Kind::Metrics, None)
+    }
+
+    #[test]
+    fn same_client_id_gets_distinct_gateway_ids() {
+        let mut table = PendingTable::default();
+        let mut req = request(1);
+        req.headers.insert(CORRELATION_ID, "abc");
+
+        let first = table.insert(&req, Duration::from_secs(5));
+        let second = table.insert(&request(1), Duration::from_secs(5));
+        assert_ne!(first, second);
+        assert!(table.contains(first) && table.contains(second));
+
+        let pending = table.resolve(first).unwrap();
+        assert_eq!(pending.id, 1);
+        assert_eq!(pending.correlation.as_deref(), Some("abc"));
+        assert!(!table.contains(first));
+        assert!(table.resolve(first).is_none());
+        assert_eq!(table.resolve(second).unwrap().correlation, None);
+    }
+
+    #[test]
+    fn reserved_ids_are_never_pending() {
+        let mut table = PendingTable::default();
+        let reserved = reserve_id();
+        let pending = table.insert(&request(reserved), Duration::from_secs(60));
+
+        assert_ne!(reserved, pending);
+        assert!(!table.contains(reserved));
+        assert_eq!(table.resolve(pending).unwrap().id, reserved);
+    }
+
+    #[test]
+    fn only_requests_past_their_deadline_expire() {
+        let mut table = PendingTable::default();
+        let soon = table.insert(&request(1), Duration::ZERO);
+        let later = table.insert(&request(2), Duration::from_secs(60));
+
+        let expired = table.expired();
+        assert_eq!(expired.iter().map(|p| p.id).collect::<Vec<_>>(), vec![1]);
+        assert!(!table.contains(soon));
+        assert!(table.contains(later));
+        assert!(table.expired().is_empty());
+    }
+
+    #[test]
+    fn drain_empties_the_table() {
+        let mut table = PendingTable::default();
+        let id = table.insert(&request(1), Duration::from_secs(60));
+        table.insert(&request(2), Duration::from_secs(60));
+
+        let mut ids: Vec<usize> = table.drain().iter().map(|p| p.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(!table.contains(id));
+        assert!(table.drain().is_empty());
+    }
+}
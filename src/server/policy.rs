@@ -0,0 +1,372 @@
+//! Who may do what on the gateway.
+//!
+//! The policy file maps users and groups of the connecting process, as
+//! the kernel reports them through `SO_PEERCRED`, and the subjects of TLS
+//! client certificates to roles. A role is a list of rules naming the
+//! actions, and optionally kinds, it may send:
+//!
+//! ```json
+//! {
+//!     "roles": {
+//!         "telemetry": [{ "action": "SUBSCRIBE" }, { "action": "UNSUBSCRIBE" }],
+//!         "admin": [{ "action": "*" }]
+//!     },
+//!     "users": { "root": "admin" },
+//!     "groups": { "telemetry": "telemetry", "admin": "admin" },
+//!     "certs": { "dashboard": "telemetry" },
+//!     "units": { "operations": "admin" },
+//!     "default": null
+//! }
+//! ```
+//!
+//! Users and groups are names or numeric ids and only match local
+//! processes. `certs` names the common name of a client certificate and
+//! `units` its organizational units, so a certificate for `root` is not
+//! the local root. Remote peers without one only get the `default` role,
+//! without a policy they get nothing at all. A caller gets
+//! every rule of every role it has, or of the `default` role if it has
+//! none. `HELLO` is always allowed, responses and events a client sends
+//! are checked as the `RESPOND` and `PUBLISH` actions. Responses have no
+//! kind, so only rules without one allow `RESPOND`.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use nix::unistd::{getgrouplist, Gid, Group, Uid, User};
+use serde::Deserialize;
+use tokio::net::unix::UCred;
+
+use crate::proto::types::{Action, Kind};
+use crate::server::subscriptions::ANY;
+
+/// Checked for responses a client sends to BLE.
+pub const RESPOND: &str = "RESPOND";
+/// Checked for events a client sends to BLE.
+pub const PUBLISH: &str = "PUBLISH";
+
+/// Who is on the other end of a connection: a local process, or a remote
+/// peer known by its client certificate, if any.
+#[derive(Debug, Clone, Default)]
+pub struct Identity {
+    pub uid: Option<u32>,
+    pub pid: Option<i32>,
+    pub user: Option<String>,
+    pub groups: Vec<String>,
+    /// Primary and supplementary gids.
+    pub gids: Vec<u32>,
+    pub remote: Option<SocketAddr>,
+    pub certificate: Option<Certificate>,
+}
+
+/// Subject of a verified client certificate.
+#[derive(Debug, Clone, Default)]
+pub struct Certificate {
+    pub common_name: Option<String>,
+    /// Organizational units.
+    pub units: Vec<String>,
+}
+
+impl Identity {
+    /// Looks up the user and groups of `cred`, ids are kept for whatever
+    /// does not resolve.
+    pub fn from_cred(cred: &UCred) -> Self {
+        let user = User::from_uid(Uid::from_raw(cred.uid())).ok().flatten();
+        let primary = Gid::from_raw(cred.gid());
+
+        let mut gids = vec![primary];
+        let supplementary = user.as_ref()
+            .and_then(|u| CString::new(u.name.as_str()).ok())
+            .and_then(|name| getgrouplist(&name, primary).ok());
+        for gid in supplementary.unwrap_or_default() {
+            if !gids.contains(&gid) {
+                gids.push(gid);
+            }
+        }
+
+        let groups = gids.iter()
+            .filter_map(|gid| Group::from_gid(*gid).ok().flatten().map(|g| g.name))
+            .collect();
+
+        Self {
+            uid: Some(cred.uid()),
+            pid: cred.pid(),
+            user: user.map(|u| u.name),
+            groups,
+            gids: gids.into_iter().map(Gid::as_raw).collect(),
+            remote: None,
+            certificate: None,
+        }
+    }
+
+    /// Somebody who did not say who they are.
+    pub fn anonymous(remote: Option<SocketAddr>) -> Self {
+        Self { remote, ..Self::default() }
+    }
+
+    fn is_user(&self, key: &str) -> bool {
+        self.user.as_deref() == Some(key) || self.uid.is_some_and(|uid| key == uid.to_string())
+    }
+
+    fn in_group(&self, key: &str) -> bool {
+        self.groups.iter().any(|name| name == key) || self.gids.iter().any(|gid| key == gid.to_string())
+    }
+
+    fn has_cert(&self, key: &str) -> bool {
+        self.certificate.as_ref().is_some_and(|cert| cert.common_name.as_deref() == Some(key))
+    }
+
+    fn in_unit(&self, key: &str) -> bool {
+        self.certificate.as_ref().is_some_and(|cert| cert.units.iter().any(|unit| unit == key))
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(remote) = self.remote {
+            write!(f, "{} ", remote)?;
+        }
+        if let Some(uid) = self.uid {
+            write!(f, "uid={} ", uid)?;
+        }
+        if let Some(pid) = self.pid {
+            write!(f, "pid={} ", pid)?;
+        }
+        if let Some(cert) = &self.certificate {
+            return write!(f, "cert={} units=[{}]", cert.common_name.as_deref().unwrap_or("-"), cert.units.join(","));
+        }
+        write!(f, "user={} groups=[{}]", self.user.as_deref().unwrap_or("-"), self.groups.join(","))
+    }
+}
+
+/// Allows an action, on any kind unless `kind` is set. `*` or a missing
+/// field match anything, a rule with a kind never matches messages that
+/// have none. Names are matched ignoring case.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Option<String>,
+    pub kind: Option<String>,
+}
+
+impl Rule {
+    fn allows(&self, action: &Action, kind: Option<&Kind>) -> bool {
+        let action_ok = match self.action.as_deref() {
+            None | Some(ANY) => true,
+            Some(a) => a.eq_ignore_ascii_case(action.as_str()),
+        };
+        let kind_ok = match (self.kind.as_deref(), kind) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(ANY), Some(_)) => true,
+            (Some(k), Some(kind)) => k.eq_ignore_ascii_case(kind.as_str()),
+        };
+
+        action_ok && kind_ok
+    }
+}
+
+/// The roles of one connection and what they allow.
+#[derive(Debug, Clone, Default)]
+pub struct Permissions {
+    pub roles: Vec<String>,
+    rules: Vec<Rule>,
+}
+
+impl Permissions {
+    /// Everything is allowed, used when there is no policy.
+    pub fn unrestricted() -> Self {
+        Self { roles: Vec::new(), rules: vec![Rule::default()] }
+    }
+
+    /// Whether the client may send `action`, `kind` is `None` for
+    /// messages that have none.
+    pub fn allows(&self, action: &Action, kind: Option<&Kind>) -> bool {
+        *action == Action::Hello || self.rules.iter().any(|rule| rule.allows(action, kind))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    roles: HashMap<String, Vec<Rule>>,
+    /// User to role.
+    #[serde(default)]
+    users: HashMap<String, String>,
+    /// Group to role.
+    #[serde(default)]
+    groups: HashMap<String, String>,
+    /// Certificate common name to role.
+    #[serde(default)]
+    certs: HashMap<String, String>,
+    /// Certificate organizational unit to role.
+    #[serde(default)]
+    units: HashMap<String, String>,
+    /// Role of callers that have none.
+    #[serde(default)]
+    default: Option<String>,
+}
+
+impl Policy {
+    /// Reads the policy file, every role it hands out must be defined.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let policy: Policy = serde_json::from_str(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+
+        let undefined = [&policy.users, &policy.groups, &policy.certs, &policy.units].into_iter()
+            .flat_map(HashMap::values)
+            .chain(policy.default.iter())
+            .find(|role| !policy.roles.contains_key(*role));
+        if let Some(role) = undefined {
+            let e = format!("{}: role {:?} is not defined", path.display(), role);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        Ok(policy)
+    }
+
+    pub fn permissions(&self, identity: &Identity) -> Permissions {
+        let mut roles: Vec<String> = Vec::new();
+        let users = self.users.iter().filter(|(user, _)| identity.is_user(user));
+        let groups = self.groups.iter().filter(|(group, _)| identity.in_group(group));
+        let certs = self.certs.iter().filter(|(cert, _)| identity.has_cert(cert));
+        let units = self.units.iter().filter(|(unit, _)| identity.in_unit(unit));
+        for (_, role) in users.chain(groups).chain(certs).chain(units) {
+            if !roles.contains(role) {
+                roles.push(role.clone());
+            }
+        }
+
+        if roles.is_empty() {
+            roles.extend(self.default.clone());
+        }
+
+        let rules = roles.iter().flat_map(|role| self.roles[role].iter().cloned()).collect();
+        Permissions { roles, rules }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> Policy {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn user(name: &str, groups: &[&str]) -> Identity {
+        Identity {
+            user: Some(name.to_string()),
+            groups: groups.iter().map(|g| g.to_string()).collect(),
+            ..Identity::default()
+        }
+    }
+
+    const POLICY: &str = r#"{
+        "roles": {
+            "reader": [{ "action": "GET" }],
+            "writer": [{ "action": "SET", "kind": "metrics" }, { "action": "PUBLISH", "kind": "*" }],
+            "guest": [{ "action": "SUBSCRIBE" }]
+        },
+        "users": { "alice": "writer", "1000": "reader" },
+        "groups": { "staff": "reader" },
+        "default": "guest"
+    }"#;
+
+    #[test]
+    fn roles_of_user_and_groups_add_up() {
+        let permissions = policy(POLICY).permissions(&user("alice", &["staff"]));
+        assert_eq!(permissions.roles, vec!["writer".to_string(), "reader".to_string()]);
+        assert!(permissions.allows(&Action::Get, Some(&Kind::Gateway)));
+        assert!(permissions.allows(&Action::Set, Some(&Kind::Metrics)));
+        assert!(!permissions.allows(&Action::Set, Some(&Kind::Gateway)));
+        assert!(!permissions.allows(&Action::Subscribe, Some(&Kind::Gateway)));
+    }
+
+    #[test]
+    fn numeric_ids_match() {
+        let identity = Identity { uid: Some(1000), ..Identity::default() };
+        assert_eq!(policy(POLICY).permissions(&identity).roles, vec!["reader".to_string()]);
+    }
+
+    #[test]
+    fn callers_without_a_role_get_the_default() {
+        let permissions = policy(POLICY).permissions(&user("bob", &[]));
+        assert_eq!(permissions.roles, vec!["guest".to_string()]);
+        assert!(permissions.allows(&Action::Subscribe, Some(&Kind::Gateway)));
+        assert!(!permissions.allows(&Action::Get, Some(&Kind::Gateway)));
+
+        let strict = policy(r#"{ "roles": {} }"#).permissions(&user("bob", &[]));
+        assert!(strict.roles.is_empty());
+        assert!(!strict.allows(&Action::Get, Some(&Kind::Gateway)));
+    }
+
+    #[test]
+    fn certificates_do_not_match_local_users() {
+        let policy = policy(r#"{
+            "roles": { "admin": [{ "action": "*" }], "reader": [{ "action": "GET" }] },
+            "users": { "root": "admin" },
+            "groups": { "admin": "admin" },
+            "certs": { "dashboard": "reader" },
+            "units": { "ops": "admin" }
+        }"#);
+        let remote = |common_name: &str, units: &[&str]| Identity {
+            certificate: Some(Certificate {
+                common_name: Some(common_name.to_string()),
+                units: units.iter().map(|u| u.to_string()).collect(),
+            }),
+            ..Identity::anonymous(Some(([127, 0, 0, 1], 9000).into()))
+        };
+
+        assert!(policy.permissions(&remote("root", &["admin"])).roles.is_empty());
+        assert_eq!(policy.permissions(&remote("dashboard", &[])).roles, vec!["reader".to_string()]);
+        assert_eq!(policy.permissions(&remote("someone", &["ops"])).roles, vec!["admin".to_string()]);
+        assert!(policy.permissions(&user("dashboard", &["ops"])).roles.is_empty());
+    }
+
+    #[test]
+    fn rules_ignore_case() {
+        let policy = policy(r#"{
+            "roles": { "device": [{ "action": "publish", "kind": "Door" }, { "action": "respond" }, { "action": "get", "kind": "METRICS" }] },
+            "default": "device"
+        }"#);
+        let permissions = policy.permissions(&user("bob", &[]));
+
+        assert!(permissions.allows(&Action::from(PUBLISH), Some(&Kind::from("door"))));
+        assert!(permissions.allows(&Action::from(RESPOND), None));
+        assert!(permissions.allows(&Action::Get, Some(&Kind::Metrics)));
+        assert!(!permissions.allows(&Action::Set, Some(&Kind::Metrics)));
+    }
+
+    #[test]
+    fn hello_is_always_allowed() {
+        assert!(Permissions::default().allows(&Action::Hello, Some(&Kind::Gateway)));
+    }
+
+    #[test]
+    fn kind_rules_do_not_match_messages_without_kind() {
+        let permissions = policy(POLICY).permissions(&user("alice", &[]));
+        assert!(!permissions.allows(&Action::from(RESPOND), None));
+        assert!(!permissions.allows(&Action::from(PUBLISH), None));
+        assert!(permissions.allows(&Action::from(PUBLISH), Some(&Kind::Gateway)));
+
+        let any = policy(r#"{ "roles": { "all": [{ "action": "*" }] }, "default": "all" }"#);
+        assert!(any.permissions(&user("bob", &[])).allows(&Action::from(RESPOND), None));
+    }
+
+    #[test]
+    fn undefined_roles_are_rejected() {
+        let path = std::env::temp_dir().join(format!("policy-test-{}.json", std::process::id()));
+        fs::write(&path, r#"{ "roles": {}, "groups": { "staff": "reader" } }"#).unwrap();
+        let err = Policy::load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("\"reader\" is not defined"));
+    }
+}
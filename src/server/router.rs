@@ -0,0 +1,183 @@
+//! Dispatches requests on their `(action, kind)` pair.
+//!
+//! Handlers decide whether the gateway answers a request itself, forwards
+//! it to BLE for the device to answer, or both. Requests nobody registered
+//! for get a 404, or a 405 when the kind is only routed for other actions.
+
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::proto::msg::{Request, Response};
+use crate::proto::types::{Action, Kind};
+
+/// What to do with a request.
+pub enum Handled {
+    /// The gateway answers.
+    Local(Response),
+    /// Forwarded to BLE, the device's response goes back to the client.
+    Forward,
+    /// Forwarded to BLE without waiting for the device, the gateway answers.
+    Both(Response),
+}
+
+pub trait Handler: Send + Sync {
+    fn handle(&self, req: &Request) -> Handled;
+}
+
+/// A handler that gets the request body already decoded.
+pub trait TypedHandler: Send + Sync {
+    type Body: DeserializeOwned;
+
+    fn handle(&self, req: &Request, body: Self::Body) -> Handled;
+}
+
+/// Adapts a `TypedHandler`, requests whose body does not decode get a 422.
+pub struct Typed<H>(pub H);
+
+impl<H: TypedHandler> Handler for Typed<H> {
+    fn handle(&self, req: &Request) -> Handled {
+        match req.body_as::<H::Body>() {
+            Ok(body) => self.0.handle(req, body),
+            Err(e) => Handled::Local(req.response(e.code(), e.reason(), Some(e.body()))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Router {
+    routes: HashMap<(Action, Kind), Arc<dyn Handler>>,
+    /// Handlers taking every kind of an action.
+    any_kind: HashMap<Action, Arc<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes `(action, kind)` to `handler`, replacing any previous one.
+    pub fn register(&mut self, action: Action, kind: Kind, handler: Arc<dyn Handler>) -> &mut Self {
+        self.routes.insert((action, kind), handler);
+        self
+    }
+
+    /// Routes `action` to `handler` for kinds without a route of their own.
+    pub fn register_any_kind(&mut self, action: Action, handler: Arc<dyn Handler>) -> &mut Self {
+        self.any_kind.insert(action, handler);
+        self
+    }
+
+    pub fn route(&self, req: &Request) -> Handled {
+        let exact = (req.action.clone(), req.kind.clone());
+        if let Some(handler) = self.routes.get(&exact) {
+            return handler.handle(req);
+        }
+
+        // Kinds with routes of their own never fall through to `any_kind`
+        let allowed: BTreeSet<&str> = self
+            .routes
+            .keys()
+            .filter(|(_, kind)| *kind == req.kind)
+            .map(|(action, _)| action.as_str())
+            .collect();
+
+        if !allowed.is_empty() {
+            return Handled::Local(req.response(405, "Method Not Allowed", Some(json!({
+                "error": format!("{} not allowed on {}", req.action, req.kind),
+                "allow": allowed,
+            }))));
+        }
+
+        match self.any_kind.get(&req.action) {
+            Some(handler) => handler.handle(req),
+            None => Handled::Local(req.response(404, "Not Found", Some(json!({
+                "error": format!("no route for {} {}", req.action, req.kind),
+            })))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::bodies::Empty;
+    use crate::proto::msg::{PROTO_NAME, PROTO_NUMBER};
+
+    struct Answer(usize);
+
+    impl Handler for Answer {
+        fn handle(&self, req: &Request) -> Handled {
+            Handled::Local(req.response(self.0, "OK", None))
+        }
+    }
+
+    struct Strict;
+
+    impl TypedHandler for Strict {
+        type Body = Empty;
+
+        fn handle(&self, req: &Request, _body: Empty) -> Handled {
+            Handled::Local(req.response(200, "OK", None))
+        }
+    }
+
+    fn request(action: &str, kind: &str, body: Option<serde_json::Value>) -> Request {
+        Request::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), 1, Action::from(action), Kind::from(kind), body)
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router
+            .register(Action::Get, Kind::Metrics, Arc::new(Answer(200)))
+            .register(Action::Set, Kind::Metrics, Arc::new(Answer(201)))
+            .register(Action::Get, Kind::Gateway, Arc::new(Typed(Strict)))
+            .register_any_kind(Action::Get, Arc::new(Answer(202)));
+        router
+    }
+
+    fn local(handled: Handled) -> Response {
+        match handled {
+            Handled::Local(response) => response,
+            _ => panic!("expected a local response"),
+        }
+    }
+
+    #[test]
+    fn exact_route_wins() {
+        assert_eq!(local(router().route(&request("GET", "metrics", None))).code, 200);
+        assert_eq!(local(router().route(&request("set", "METRICS", None))).code, 201);
+    }
+
+    #[test]
+    fn any_kind_takes_unrouted_kinds() {
+        assert_eq!(local(router().route(&request("GET", "temperature", None))).code, 202);
+    }
+
+    #[test]
+    fn routed_kind_with_other_action_is_405() {
+        let response = local(router().route(&request("SUBSCRIBE", "metrics", None)));
+        assert_eq!(response.code, 405);
+        assert_eq!(response.body.unwrap()["allow"], serde_json::json!(["GET", "SET"]));
+
+        // Even if the action has an any-kind handler
+        let response = local(router().route(&request("SET", "gateway", None)));
+        assert_eq!(response.code, 405);
+        assert_eq!(response.body.unwrap()["allow"], serde_json::json!(["GET"]));
+    }
+
+    #[test]
+    fn unrouted_action_is_404() {
+        let response = local(router().route(&request("SET", "temperature", None)));
+        assert_eq!(response.code, 404);
+        assert_eq!(response.id, 1);
+    }
+
+    #[test]
+    fn typed_handler_rejects_bad_body_with_422() {
+        let response = local(router().route(&request("GET", "gateway", Some(json!({ "unexpected": 1 })))));
+        assert_eq!(response.code, 422);
+        assert_eq!(local(router().route(&request("GET", "gateway", None))).code, 200);
+    }
+}
@@ -1,308 +1,154 @@
-use futures::{
-    SinkExt,
-    StreamExt
-};
-use tokio::task;
-use tokio::net::{UnixListener, UnixStream};
-use tokio_util::codec::{Framed};
 //use tokio_stream; 
-use tokio::sync::{
-    broadcast
-};
-use bytes::{Bytes, BytesMut, BufMut};
-use std::fs;
-use crate::server::mcodec::{TwoByteLenSkipReserved, MAX_FRAME_SIZE};
+use tokio::signal::unix::{signal, SignalKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use crate::server::config::ServerConfig;
+use crate::server::connection::{handle_connection, Shared};
+use crate::server::handlers::default_router;
+use crate::server::metrics::Metrics;
+use crate::server::origin::Origins;
+use crate::server::policy::Policy;
+use crate::server::{socket, tcp, websocket};
 //use crate::server::peer::{Peer, PeerPair};
-use crate::proto::msg::{Message, Response, decode_message};
+use crate::proto::headers::SOURCE;
+use crate::proto::msg::{Message, decode_message};
+use crate::bus::{fanout::RecvError, Fanout, FromBle, Subscriber, ToBle, ToClient};
 
 
-const SOCKET_FILE: &str = "/tmp/gateway.sock";
+pub async fn run(config: ServerConfig, from_ble: Subscriber<FromBle>, broadcaster: Fanout::<ToBle>) -> std::io::Result<()> {
+    // The socket goes away with the guard when run returns
+    let (listener, _socket) = socket::bind(&config.socket)?;
+    println!("listening on {}", config.socket.path.display());
 
-
-pub async fn run(provider: broadcast::Sender::<Bytes>, broadcaster: broadcast::Sender::<Bytes>) -> std::io::Result<()> {
-    let _ = fs::remove_file(SOCKET_FILE);
-
-    let listener = UnixListener::bind(SOCKET_FILE)?;
-
-    loop {
-        let (stream, _) = listener.accept().await?;
-
-        let transmitter = broadcaster.clone();
-        let subs = provider.subscribe();
-        
-        tokio::spawn(async move {
-            handle_connection(stream, subs, transmitter).await;
-        });
-    }
-}
-
-async fn handle_connection(stream: UnixStream, mut subs: broadcast::Receiver<Bytes>, transmitter: broadcast::Sender<Bytes>) {
-    // Use FramedRead (read-only) with our codec
-    //let reader = FramedRead::new(stream, TwoByteLenSkipReserved::new(MAX_FRAME_SIZE));
-    // use a single Framed (Stream + Sink) to read frames and write responses
-    //let mut reader_writer = Framed::new(stream, TwoByteLenSkipReserved::new(MAX_FRAME_SIZE));
-    let codec = TwoByteLenSkipReserved::new(MAX_FRAME_SIZE);
-    let framed = Framed::new(stream, codec);
-
-    // split into sink (writer) and stream (reader)
-    let (mut sink, mut source) = framed.split();
-
-    //let (tx, rx) = mpsc::channel(16);
-
-    let _reader_task = task::spawn(async move {
-        while let Some(frame_res) = source.next().await {
-            match frame_res {
-                Ok(bytes_payload) => {
-                    // Rebuild the frame and forward to BLE
-                    let mut forward_frame = BytesMut::new();
-                    forward_frame.put_slice(&[0xff, 0xff]);
-                    let len_field = bytes_payload.len() as u16;
-                    forward_frame.put_slice(&len_field.to_be_bytes());
-                    forward_frame.put(bytes_payload.clone());
-                    if let Err(e) = transmitter.send(forward_frame.freeze()) {
-                        println!("transmitter err: {}", e);
-                    }
-
-                    let _r = match decode_message(bytes_payload) {
-                        Ok(m) => {
-                            match m {
-                                Message::Request(req) => {
-                                    println!("req=\n{}", req);
-                                    /*if let Err(e) = transmitter.send(Bytes::from(req.encode())) {
-                                        println!("transmitter err: {}", e);
-                                    }*/
-
-                                    let response = Response::new(
-                                        req.protocol,
-                                        req.version,
-                                        req.id,
-                                        200,
-                                        "OK".to_string(),
-                                        None
-                                    );
-
-                                    let s = response.encode();
-                                    println!("will respond: {}", s);
-
-                                    let payload = Bytes::from(s);
-
-                                    if let Err(e) = sink.send(payload).await {
-                                        eprintln!("could not send response: {}", e);
-                                        return;
-                                    }
-                                }
-
-                                Message::Response(resp) => println!("resp=\n{}", resp)
-                            }
-
-                        },
-                        Err(e) => {
-                            eprintln!("could not decode message {}", e);
-                        }
-                    };
-                }
-                Err(e) => {
-                    eprintln!("frame read error: {}", e);
-                    //break; // or continue depending on desired policy
-                }
-            }
+    let tcp = match &config.tcp {
+        Some(tcp_config) => {
+            let (tcp_listener, tls) = tcp::bind(tcp_config).await?;
+            println!("listening on {}{}", tcp_config.addr, if tls.is_some() { " with tls" } else { "" });
+            Some((tcp_listener, tls))
         }
-    });
+        None => None,
+    };
+
+    let websocket = match config.websocket {
+        Some(addr) => {
+            let ws_listener = TcpListener::bind(addr).await?;
+            println!("listening on ws://{}", addr);
+            Some(ws_listener)
+        }
+        None => None,
+    };
 
-    let _writer_task = task::spawn(async move {
-        loop {
-            let ble_msg = subs.recv().await;
-            match ble_msg {
-                Ok(m) => {
-                    println!("recv ble msg: {:?}", m);
-                    /*let response = Response::new(
-                        req.protocol,
-                        req.version,
-                        req.id,
-                        200,
-                        "OK".to_string(),
-                        None
-                    );
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
 
-                    let s = response.encode();
-                    println!("will respond: {}", s);
+    // Connections get BLE messages once they went through dispatch_ble.
+    // Only it holds the sender, so connections see the bus close with it,
+    // and it stops once BLE is gone.
+    let origins = Arc::new(Origins::default());
+    let client_broadcaster = Fanout::<ToClient>::new();
 
-                    let payload = Bytes::from(s);
+    let metrics = Arc::new(Metrics::new(client_broadcaster.stats(), broadcaster.stats()));
 
-                    if let Err(e) = sink.send(payload).await {
-                        eprintln!("could not send response: {}", e);
-                        return;
-                    }*/
-                }
-                Err(e) => {
-                    eprintln!("subs recv err: {}", e);
-                }
-            }
+    let policy = match &config.policy {
+        Some(path) => {
+            println!("peers are checked against {}", path.display());
+            Some(Policy::load(path)?)
+        }
+        None => {
+            println!("no policy, local peers may do everything, remote ones nothing");
+            None
         }
+    };
+
+    let shared = Arc::new(Shared {
+        clients: client_broadcaster.downgrade(),
+        transmitter: broadcaster,
+        router: default_router(metrics.clone()),
+        origins: origins.clone(),
+        metrics: metrics.clone(),
+        policy,
+        config: config.clone(),
     });
 
-    /*loop {
-        tokio::select! {
-            Some(frame_res) = source.next() => {
-                match frame_res {
-                    Ok(bytes_payload) => {
-                        let _r = match decode_message(bytes_payload) {
-                            Ok(m) => {
-                                match m {
-                                    Message::Request(req) => {
-                                        println!("req=\n{}", req);
-                                        if let Err(e) = transmitter.send(Bytes::from("sent a request to channel")) {
-                                            println!("transmitter err: {}", e);
-                                        }
-
-                                        let response = Response::new(
-                                            req.protocol,
-                                            req.version,
-                                            req.id,
-                                            200,
-                                            "OK".to_string(),
-                                            None
-                                        );
+    tokio::spawn(dispatch_ble(from_ble, client_broadcaster, origins, metrics));
 
-                                        let s = response.encode();
-                                        println!("will respond: {}", s);
-
-                                        let payload = Bytes::from(s);
-                                        if let Err(e) = sink.send(payload).await {
-                                            eprintln!("could not send response: {}", e);
-                                            return;
-                                        }
-                                    }
-
-                                    Message::Response(resp) => println!("resp=\n{}", resp)
-                                }
-
-                            },
-                            Err(e) => {
-                                eprintln!("could not decode message {}", e);
-                            }
-                        };
-                    }
-                    Err(e) => {
-                        eprintln!("frame read error: {}", e);
-                        //break; // or continue depending on desired policy
-                    }
-                }
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let identity = socket::peer_identity(&stream);
+                tokio::spawn(handle_connection(stream, identity, shared.clone()));
             }
-
-            ble_msg = subs.recv() => {
-                match ble_msg {
-                    Ok(m) => println!("recv ble msg: {:?}", m),
-                    Err(e) => eprintln!("subs recv err: {}", e)
+            accepted = accept_tcp(tcp.as_ref().map(|(listener, _)| listener)) => match accepted {
+                Ok((stream, remote)) => {
+                    let tls = tcp.as_ref().and_then(|(_, tls)| tls.clone());
+                    tokio::spawn(tcp::accept(stream, remote, tls, shared.clone()));
                 }
-            }
-        }
-    }*/
-
-
-    /*while let Some(frame_res) = source.next().await {
-        match frame_res {
-            Ok(bytes_payload) => {
-                let _r = match decode_message(bytes_payload) {
-                    Ok(m) => {
-                        match m {
-                            Message::Request(req) => {
-                                println!("req=\n{}", req);
-                                if let Err(e) = transmitter.send(Bytes::from("sent a request to channel")) {
-                                    println!("transmitter err: {}", e);
-                                }
-
-                                let response = Response::new(
-                                    req.protocol,
-                                    req.version,
-                                    req.id,
-                                    200,
-                                    "OK".to_string(),
-                                    None
-                                );
-
-                                let s = response.encode();
-                                println!("will respond: {}", s);
-
-                                let payload = Bytes::from(s);
-                                if let Err(e) = sink.send(payload).await {
-                                    eprintln!("could not send response: {}", e);
-                                    return;
-                                }
-                            }
-
-                            Message::Response(resp) => println!("resp=\n{}", resp)
-                        }
-
-                    },
-                    Err(e) => {
-                        eprintln!("could not decode message {}", e);
-                    }
-                };
-            }
-            Err(e) => {
-                eprintln!("frame read error: {}", e);
-                break; // or continue depending on desired policy
-            }
+                // Usually about that one connection, the listener carries on
+                Err(e) => eprintln!("tcp accept failed: {}", e),
+            },
+            accepted = accept_tcp(websocket.as_ref()) => match accepted {
+                Ok((stream, remote)) => {
+                    tokio::spawn(websocket::accept(stream, remote, shared.clone()));
+                }
+                Err(e) => eprintln!("websocket accept failed: {}", e),
+            },
+            _ = interrupt.recv() => break,
+            _ = terminate.recv() => break,
         }
-    }*/
+    }
 
-    println!("connection closed");
+    println!("shutting down, removing {}", config.socket.path.display());
+    Ok(())
 }
 
+/// Next connection on a TCP listener, never if there is none.
+async fn accept_tcp(listener: Option<&TcpListener>) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
 
-/*async fn handle_connection(stream: UnixStream, subs: broadcast::Receiver<Bytes>, transmitter: broadcast::Sender<Bytes>) {
-    // Use FramedRead (read-only) with our codec
-    //let reader = FramedRead::new(stream, TwoByteLenSkipReserved::new(MAX_FRAME_SIZE));
-    // use a single Framed (Stream + Sink) to read frames and write responses
-    let mut reader_writer = Framed::new(stream, TwoByteLenSkipReserved::new(MAX_FRAME_SIZE));
-    
-    while let Some(frame_res) = reader_writer.next().await {
-        match frame_res {
-            Ok(bytes_payload) => {
-                let _r = match decode_message(bytes_payload) {
-                    Ok(m) => {
-                        match m {
-                            Message::Request(req) => {
-                                println!("req=\n{}", req);
-                                if let Err(e) = transmitter.send(Bytes::from("sent a request to channel")) {
-                                    println!("transmitter err: {}", e);
-                                }
-
-                                let response = Response::new(
-                                    req.protocol,
-                                    req.version,
-                                    req.id,
-                                    200,
-                                    "OK".to_string(),
-                                    None
-                                );
-
-                                let s = response.encode();
-                                println!("will respond: {}", s);
-
-                                let payload = Bytes::from(s);
-                                if let Err(e) = reader_writer.send(payload).await {
-                                    eprintln!("could not send response: {}", e);
-                                    return;
-                                }
-                            }
-
-                            Message::Response(resp) => println!("resp=\n{}", resp)
-                        }
-
-                    },
-                    Err(e) => {
-                        eprintln!("could not decode message {}", e);
+/// Hands messages from BLE to every connection. Requests get a gateway-wide
+/// id first, so the response to them can find its way back to the central.
+/// Requests and events get the central's address as their `Source` header.
+///
+/// A client that blocks the bus holds this up, which in turn fills the
+/// queue from BLE. Unless that one blocks as well, BLE messages are lost
+/// there and counted as `ble_lagged`.
+async fn dispatch_ble(mut from_ble: Subscriber<FromBle>, to_clients: Fanout<ToClient>, origins: Arc<Origins>, metrics: Arc<Metrics>) {
+    loop {
+        match from_ble.recv().await {
+            Ok(msg) => {
+                let message = match decode_message(msg.payload.clone()) {
+                    Ok(Message::Request(mut req)) => {
+                        req.id = origins.register(msg.session, req.id);
+                        req.headers.insert(SOURCE, &msg.session.to_string());
+                        Some(Message::Request(req))
+                    }
+                    Ok(Message::Event(mut event)) => {
+                        event.headers.insert(SOURCE, &msg.session.to_string());
+                        Some(Message::Event(event))
                     }
+                    Ok(message) => Some(message),
+                    Err(_) => None,
                 };
+
+                // Nobody connected is fine
+                to_clients.publish(ToClient { raw: msg.payload, message: message.map(Arc::new) }).await;
+            }
+            Err(RecvError::Closed) => break,
+            Err(RecvError::Lagged(n)) => {
+                eprintln!("dispatcher lagged behind ble, {} messages dropped", n);
+                metrics.ble_lagged.fetch_add(n, Ordering::Relaxed);
             }
-            Err(e) => {
-                eprintln!("frame read error: {}", e);
-                break; // or continue depending on desired policy
+            Err(RecvError::Disconnected) => {
+                eprintln!("dispatcher fell too far behind ble, queued messages dropped");
+                from_ble.reconnect();
             }
         }
     }
-
-    println!("connection closed");
-}*/
+}
@@ -0,0 +1,144 @@
+//! Creating the Unix socket clients connect to, and removing it again.
+//!
+//! A lock file next to the socket is held for as long as the server runs.
+//! A second instance fails to take it instead of deleting the socket of the
+//! first one from under it.
+
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::server::config::SocketConfig;
+use crate::server::policy::Identity;
+
+/// Removes the socket when dropped, the lock is released with it.
+pub struct SocketGuard {
+    path: PathBuf,
+    _lock: File,
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            eprintln!("could not remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Who connected, from the socket's `SO_PEERCRED`.
+pub fn peer_identity(stream: &UnixStream) -> Identity {
+    match stream.peer_cred() {
+        Ok(cred) => Identity::from_cred(&cred),
+        Err(e) => {
+            eprintln!("could not get peer credentials: {}", e);
+            Identity::anonymous(None)
+        }
+    }
+}
+
+/// Takes the lock, then binds the socket with the configured mode and owner.
+pub fn bind(config: &SocketConfig) -> io::Result<(UnixListener, SocketGuard)> {
+    let lock_path = config.lock_path();
+    // Lives in a shared directory: never truncate it, nor follow a link planted there
+    let lock = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .mode(0o600)
+        .custom_flags(nix::libc::O_NOFOLLOW)
+        .open(&lock_path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", lock_path.display(), e)))?;
+    lock.try_lock().map_err(|e| {
+        io::Error::other(format!("{} is locked, is another gateway running? ({})", lock_path.display(), e))
+    })?;
+
+    // Nobody else holds the lock, so whatever is there is left over
+    match fs::remove_file(&config.path) {
+        Ok(()) => println!("removed stale socket {}", config.path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    // Nobody but us may connect until the mode is set
+    let old_mask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(&config.path);
+    umask(old_mask);
+    let listener = listener?;
+
+    let guard = SocketGuard { path: config.path.clone(), _lock: lock };
+
+    let owner = config.owner.as_deref().map(resolve_user).transpose()?;
+    let group = config.group.as_deref().map(resolve_group).transpose()?;
+    if owner.is_some() || group.is_some() {
+        chown(&config.path, owner, group).map_err(io::Error::from)?;
+    }
+
+    fs::set_permissions(&config.path, Permissions::from_mode(config.mode))?;
+
+    Ok((listener, guard))
+}
+
+fn resolve_user(name: &str) -> io::Result<Uid> {
+    if let Ok(uid) = name.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+
+    match User::from_name(name).map_err(io::Error::from)? {
+        Some(user) => Ok(user.uid),
+        None => Err(io::Error::other(format!("no such user {:?}", name))),
+    }
+}
+
+fn resolve_group(name: &str) -> io::Result<Gid> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+
+    match Group::from_name(name).map_err(io::Error::from)? {
+        Some(group) => Ok(group.gid),
+        None => Err(io::Error::other(format!("no such group {:?}", name))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn config(name: &str) -> SocketConfig {
+        let path = std::env::temp_dir().join(format!("gateway-test-{}-{}.sock", std::process::id(), name));
+        SocketConfig { path, ..SocketConfig::default() }
+    }
+
+    #[tokio::test]
+    async fn second_bind_fails_while_the_first_runs() {
+        let config = config("twice");
+        let (_listener, guard) = bind(&config).unwrap();
+        assert!(bind(&config).is_err());
+        assert!(config.path.exists());
+
+        drop(guard);
+        assert!(!config.path.exists());
+
+        let (_listener, _guard) = bind(&config).unwrap();
+        fs::remove_file(config.lock_path()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lock_file_links_are_not_followed() {
+        let config = config("link");
+        let target = config.path.with_extension("target");
+        fs::write(&target, "keep me").unwrap();
+        symlink(&target, config.lock_path()).unwrap();
+
+        assert!(bind(&config).is_err());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "keep me");
+        assert!(!config.path.exists());
+
+        fs::remove_file(config.lock_path()).unwrap();
+        fs::remove_file(&target).unwrap();
+    }
+}
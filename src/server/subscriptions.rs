@@ -0,0 +1,201 @@
+//! What a socket client asked to receive besides the responses to its own
+//! requests. Clients get nothing unsolicited until they subscribe.
+
+use crate::proto::bodies::{EventTopics, Topic, Topics};
+use crate::proto::headers::SOURCE;
+use crate::proto::msg::Message;
+use crate::proto::types::{Action, Kind};
+
+/// Matches any value of a topic field.
+pub const ANY: &str = "*";
+
+#[derive(Debug, Default)]
+pub struct Subscriptions {
+    topics: Vec<Topic>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, topics: Vec<Topic>) {
+        for topic in topics {
+            if !self.topics.contains(&topic) {
+                self.topics.push(topic);
+            }
+        }
+    }
+
+    /// Drops the given topics, or all of them if none are given.
+    pub fn unsubscribe(&mut self, topics: Vec<Topic>) {
+        if topics.is_empty() {
+            self.topics.clear();
+        } else {
+            self.topics.retain(|t| !topics.contains(t));
+        }
+    }
+
+    pub fn topics(&self) -> Topics {
+        Topics { topics: self.topics.clone() }
+    }
+
+    /// Whether the client wants `message`, `None` being a message that did
+    /// not decode. Those only go to clients subscribed to everything.
+    pub fn matches(&self, message: Option<&Message>) -> bool {
+        self.topics.iter().any(|topic| match message {
+            Some(message) => topic_matches(topic, message),
+            None => *topic == Topic::default(),
+        })
+    }
+}
+
+/// Topics of the `events` shorthand.
+pub fn event_topics(events: EventTopics) -> Vec<Topic> {
+    let names = events.names.into_iter().map(|name| Topic {
+        event: Some(name),
+        ..Topic::default()
+    });
+
+    let kinds = events.kinds.into_iter().map(|kind| Topic {
+        kind: Some(kind),
+        event: Some(ANY.to_string()),
+        ..Topic::default()
+    });
+
+    names.chain(kinds).collect()
+}
+
+fn field_matches(want: &Option<String>, matches: impl FnOnce(&str) -> bool) -> bool {
+    want.as_deref().is_none_or(|want| want == ANY || matches(want))
+}
+
+fn topic_matches(topic: &Topic, message: &Message) -> bool {
+    let kind = |kind: &Kind| field_matches(&topic.kind, |want| Kind::from(want) == *kind);
+
+    match message {
+        Message::Request(req) => {
+            topic.event.is_none()
+                && field_matches(&topic.action, |want| Action::from(want) == req.action)
+                && kind(&req.kind)
+                && field_matches(&topic.source, |want| req.headers.get(SOURCE) == Some(want))
+        }
+        Message::Event(event) => {
+            topic.action.is_none()
+                && field_matches(&topic.event, |want| want == event.name)
+                && kind(&event.kind)
+                && field_matches(&topic.source, |want| want == event.source || event.headers.get(SOURCE) == Some(want))
+        }
+        // Responses go to whoever sent the request
+        Message::Response(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::msg::{Event, Request, Response, PROTO_NAME, PROTO_NUMBER};
+
+    fn topic(kind: Option<&str>, action: Option<&str>, event: Option<&str>, source: Option<&str>) -> Topic {
+        Topic {
+            kind: kind.map(str::to_string),
+            action: action.map(str::to_string),
+            event: event.map(str::to_string),
+            source: source.map(str::to_string),
+        }
+    }
+
+    fn request(action: &str, kind: &str) -> Message {
+        let req = Request::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), 1, Action::from(action), Kind::from(kind), None);
+        Message::Request(req)
+    }
+
+    fn event(source: &str, kind: &str, name: &str) -> Message {
+        let event = Event::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), source.to_string(), Kind::from(kind), name.to_string(), None);
+        Message::Event(event)
+    }
+
+    #[test]
+    fn empty_topic_matches_requests_and_events() {
+        assert!(topic_matches(&Topic::default(), &request("GET", "metrics")));
+        assert!(topic_matches(&Topic::default(), &event("door", "sensor", "opened")));
+    }
+
+    #[test]
+    fn action_topics_only_match_requests() {
+        let get = topic(None, Some("GET"), None, None);
+        assert!(topic_matches(&get, &request("GET", "metrics")));
+        assert!(!topic_matches(&get, &request("SET", "metrics")));
+        assert!(!topic_matches(&get, &event("door", "sensor", "GET")));
+
+        let any = topic(None, Some(ANY), None, None);
+        assert!(topic_matches(&any, &request("SET", "metrics")));
+        assert!(!topic_matches(&any, &event("door", "sensor", "opened")));
+    }
+
+    #[test]
+    fn event_topics_only_match_events() {
+        let opened = topic(None, None, Some("opened"), None);
+        assert!(topic_matches(&opened, &event("door", "sensor", "opened")));
+        assert!(!topic_matches(&opened, &event("door", "sensor", "closed")));
+        assert!(!topic_matches(&opened, &request("GET", "metrics")));
+
+        let any = topic(Some("sensor"), None, Some(ANY), None);
+        assert!(topic_matches(&any, &event("door", "sensor", "closed")));
+        assert!(!topic_matches(&any, &event("door", "metrics", "closed")));
+        assert!(!topic_matches(&any, &request("GET", "sensor")));
+    }
+
+    #[test]
+    fn source_matches_event_source_or_header() {
+        let door = topic(None, None, None, Some("door"));
+        assert!(topic_matches(&door, &event("door", "sensor", "opened")));
+        assert!(!topic_matches(&door, &event("window", "sensor", "opened")));
+
+        let Message::Event(mut relayed) = event("hub", "sensor", "opened") else { unreachable!() };
+        relayed.headers.insert(SOURCE, "door");
+        assert!(topic_matches(&door, &Message::Event(relayed)));
+
+        let Message::Request(mut req) = request("GET", "metrics") else { unreachable!() };
+        assert!(!topic_matches(&door, &Message::Request(req.clone())));
+        req.headers.insert(SOURCE, "door");
+        assert!(topic_matches(&door, &Message::Request(req)));
+    }
+
+    #[test]
+    fn responses_never_match() {
+        let resp = Response::new(PROTO_NAME.to_string(), PROTO_NUMBER.to_string(), 1, 200, "OK".to_string(), None);
+        assert!(!topic_matches(&Topic::default(), &Message::Response(resp)));
+    }
+
+    #[test]
+    fn undecoded_messages_only_go_to_subscribers_of_everything() {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(vec![topic(None, Some("GET"), None, None)]);
+        assert!(!subs.matches(None));
+        assert!(subs.matches(Some(&request("GET", "metrics"))));
+
+        subs.subscribe(vec![Topic::default()]);
+        assert!(subs.matches(None));
+    }
+
+    #[test]
+    fn subscribe_dedups_and_unsubscribe_drops() {
+        let mut subs = Subscriptions::default();
+        let get = topic(None, Some("GET"), None, None);
+        let opened = topic(None, None, Some("opened"), None);
+        subs.subscribe(vec![get.clone(), get.clone(), opened.clone()]);
+        assert_eq!(subs.topics().topics, vec![get.clone(), opened.clone()]);
+
+        subs.unsubscribe(vec![get]);
+        assert_eq!(subs.topics().topics, vec![opened]);
+
+        subs.unsubscribe(Vec::new());
+        assert!(subs.topics().topics.is_empty());
+    }
+
+    #[test]
+    fn event_shorthand_expands_to_topics() {
+        let events = EventTopics { names: vec!["opened".to_string()], kinds: vec!["sensor".to_string()] };
+        assert_eq!(event_topics(events), vec![
+            topic(None, None, Some("opened"), None),
+            topic(Some("sensor"), None, Some(ANY), None),
+        ]);
+    }
+}
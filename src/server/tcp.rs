@@ -0,0 +1,117 @@
+//! The TCP listener, plain or TLS.
+//!
+//! Connections go through the same `handle_connection` as the Unix
+//! socket, only the identity differs: TLS clients are known by their
+//! certificate, everybody else is anonymous. Without a policy neither
+//! gets to do anything.
+
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::{self, pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::server::config::{TcpConfig, TlsConfig};
+use crate::server::connection::{handle_connection, Shared};
+use crate::server::policy::{Certificate, Identity};
+
+/// Binds the listener, and sets up TLS if configured.
+pub async fn bind(config: &TcpConfig) -> io::Result<(TcpListener, Option<TlsAcceptor>)> {
+    let tls = config.tls.as_ref().map(acceptor).transpose()?;
+    let listener = TcpListener::bind(config.addr).await?;
+
+    Ok((listener, tls))
+}
+
+/// Serves an accepted connection, after the TLS handshake if there is one.
+pub async fn accept(stream: TcpStream, remote: SocketAddr, tls: Option<TlsAcceptor>, shared: Arc<Shared>) {
+    if let Err(e) = stream.set_nodelay(true) {
+        eprintln!("could not set TCP_NODELAY for {}: {}", remote, e);
+    }
+
+    let Some(tls) = tls else {
+        return handle_connection(stream, Identity::anonymous(Some(remote)), shared).await;
+    };
+
+    let stream = match tls.accept(stream).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            eprintln!("tls handshake with {} failed: {}", remote, e);
+            return;
+        }
+    };
+
+    let identity = match stream.get_ref().1.peer_certificates().and_then(|certs| certs.first()) {
+        Some(cert) => certificate_identity(cert, remote),
+        None => Identity::anonymous(Some(remote)),
+    };
+
+    handle_connection(stream, identity, shared).await;
+}
+
+/// The subject's common name and organizational units. The certificate
+/// was verified in the handshake already.
+fn certificate_identity(cert: &CertificateDer, remote: SocketAddr) -> Identity {
+    let mut identity = Identity::anonymous(Some(remote));
+
+    match X509Certificate::from_der(cert) {
+        Ok((_, cert)) => {
+            let subject = cert.subject();
+            identity.certificate = Some(Certificate {
+                common_name: subject.iter_common_name().find_map(|cn| cn.as_str().ok()).map(str::to_string),
+                units: subject.iter_organizational_unit()
+                    .filter_map(|ou| ou.as_str().ok())
+                    .map(str::to_string)
+                    .collect(),
+            });
+        }
+        Err(e) => eprintln!("could not parse client certificate of {}: {}", remote, e),
+    }
+
+    identity
+}
+
+fn acceptor(config: &TlsConfig) -> io::Result<TlsAcceptor> {
+    let certs = load_certs(&config.cert)?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&config.key)?))?
+        .ok_or_else(|| invalid(&config.key, "no private key"))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+
+    let builder = match &config.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca)? {
+                roots.add(cert).map_err(|e| invalid(ca, e))?;
+            }
+
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if config.client_cert_optional { verifier.allow_unauthenticated() } else { verifier };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| invalid(ca, e))?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server = builder.with_single_cert(certs, key).map_err(|e| invalid(&config.cert, e))?;
+    Ok(TlsAcceptor::from(Arc::new(server)))
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates"));
+    }
+
+    Ok(certs)
+}
+
+fn invalid(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+}
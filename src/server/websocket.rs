@@ -0,0 +1,92 @@
+//! The WebSocket listener, for clients that cannot open the socket.
+//!
+//! Each WebSocket message carries one SMSG message: binary messages are
+//! the binary encoding, text messages the text one. They stand in for the
+//! frames of the socket codec, everything past that is the same
+//! connection as on the other listeners. WebSocket clients are anonymous
+//! to the policy, so they get nothing without one. Browsers are only let
+//! in from the pages listed in `GATEWAY_WS_ORIGINS`, so any other page
+//! cannot use the gateway through them.
+
+use futures::{future, SinkExt, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{header::ORIGIN, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Message as WsMessage, Utf8Bytes};
+
+use crate::server::connection::{serve, Shared};
+use crate::server::mcodec::{Frame, FrameError};
+use crate::server::policy::Identity;
+
+/// Upgrades an accepted connection and serves it.
+pub async fn accept(stream: TcpStream, remote: SocketAddr, shared: Arc<Shared>) {
+    // Same limit as the largest frame the socket codec takes
+    let config = WebSocketConfig::default().max_message_size(Some(shared.config.max_extended_frame));
+
+    let origins = &shared.config.websocket_origins;
+    // The error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    let check_origin = |req: &Request, resp: Response| match req.headers().get(ORIGIN) {
+        Some(origin) if !origins.iter().any(|o| o.as_bytes() == origin.as_bytes()) => {
+            eprintln!("websocket from {} refused, origin {:?} is not allowed", remote, origin);
+            Err(forbidden())
+        }
+        _ => Ok(resp),
+    };
+
+    let ws = match tokio_tungstenite::accept_hdr_async_with_config(stream, check_origin, Some(config)).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("websocket handshake with {} failed: {}", remote, e);
+            return;
+        }
+    };
+
+    let (sink, source) = ws.split();
+
+    let sink = sink
+        .sink_map_err(ws_error)
+        .with(|frame: Frame| future::ok::<_, FrameError>(to_ws(frame)));
+
+    // Pings are answered by tungstenite, close ends the stream
+    let source = source.filter_map(|msg| future::ready(match msg {
+        Ok(WsMessage::Binary(payload)) => Some(Ok(from_ws(payload, true))),
+        Ok(WsMessage::Text(text)) => Some(Ok(from_ws(text.into(), false))),
+        Ok(_) => None,
+        Err(e) => Some(Err(ws_error(e))),
+    }));
+
+    serve(Box::pin(sink), Box::pin(source), Identity::anonymous(Some(remote)), shared).await;
+}
+
+fn forbidden() -> ErrorResponse {
+    let mut resp = ErrorResponse::new(Some("origin not allowed".to_string()));
+    *resp.status_mut() = StatusCode::FORBIDDEN;
+    resp
+}
+
+fn from_ws(payload: bytes::Bytes, binary: bool) -> Frame {
+    let mut frame = Frame::new(payload);
+    frame.header.binary = binary;
+    frame
+}
+
+/// Text messages must be UTF-8, anything else goes out binary.
+fn to_ws(frame: Frame) -> WsMessage {
+    if frame.header.binary {
+        return WsMessage::Binary(frame.payload);
+    }
+
+    match Utf8Bytes::try_from(frame.payload.clone()) {
+        Ok(text) => WsMessage::Text(text),
+        Err(_) => WsMessage::Binary(frame.payload),
+    }
+}
+
+fn ws_error(e: tokio_tungstenite::tungstenite::Error) -> FrameError {
+    FrameError::Io(io::Error::other(e))
+}